@@ -0,0 +1,1237 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use dirs;
+use parser::{Ast, Lexer, Parser};
+use tilde;
+use variables;
+use {execute, restore_streams, with_streams, Control, Shell, Streams};
+
+/// A flag a builtin accepts before its other arguments, like `-x` for `set`.
+pub struct Flag {
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+/// The description of a builtin, which its arguments are checked against.
+pub struct Info {
+    pub name: &'static str,
+
+    /// The arguments after the name, like `[-x] [name [value...]]`.
+    pub usage: &'static str,
+
+    /// A one-line summary of what the builtin does.
+    pub description: &'static str,
+
+    /// More about how the builtin works, shown by `help` for the builtin. Can be empty.
+    pub details: &'static str,
+
+    pub flags: &'static [Flag],
+
+    /// Example command lines, shown by `help` for the builtin.
+    pub examples: &'static [&'static str],
+
+    /// The range of how many arguments the builtin takes, not counting flags.
+    pub min_args: usize,
+    pub max_args: usize,
+}
+
+/// The arguments to a builtin after they've been checked against its `Info`.
+pub struct Args<'a> {
+    /// The flags given, which are all ones the builtin accepts.
+    pub flags: Vec<&'static str>,
+
+    /// The arguments after the flags.
+    pub values: &'a [String],
+}
+
+impl<'a> Args<'a> {
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.contains(&flag)
+    }
+}
+
+/// What a builtin has access to while it runs: the whole shell, including its variables and
+/// job table, and the streams the builtin was run with after pipes and redirections.
+pub struct Context<'a> {
+    pub shell: &'a mut Shell,
+    pub streams: &'a mut Streams,
+}
+
+/// A command which runs in the shell itself rather than as a separate process.
+pub trait Builtin {
+    fn info(&self) -> &Info;
+    fn run(&self, ctx: &mut Context, args: &Args) -> i32;
+}
+
+/// A builtin made from its `Info` and a function, which is how the shell's own builtins are
+/// defined.
+struct FnBuiltin {
+    info: Info,
+    func: fn(&mut Context, &Args) -> i32,
+}
+
+impl Builtin for FnBuiltin {
+    fn info(&self) -> &Info {
+        &self.info
+    }
+
+    fn run(&self, ctx: &mut Context, args: &Args) -> i32 {
+        (self.func)(ctx, args)
+    }
+}
+
+/// Split the flags from the other arguments and check them against `info`. Flags come first,
+/// and `--` ends them. A builtin without flags takes every argument as is, so `return -1`
+/// works.
+fn parse_args<'a>(info: &Info, args: &'a [String]) -> Result<Args<'a>, String> {
+    let mut flags = vec![];
+    let mut values = args;
+
+    if !info.flags.is_empty() {
+        while let Some((arg, rest)) = values.split_first() {
+            if arg == "--" {
+                values = rest;
+                break;
+            }
+            if !arg.starts_with('-') || arg == "-" {
+                break;
+            }
+            match info.flags.iter().find(|flag| flag.name == arg) {
+                Some(flag) => flags.push(flag.name),
+                None => return Err(format!("unknown flag: {}", arg)),
+            }
+            values = rest;
+        }
+    }
+
+    if values.len() < info.min_args {
+        Err(String::from("not enough arguments"))
+    } else if values.len() > info.max_args {
+        Err(String::from("too many arguments"))
+    } else {
+        Ok(Args { flags, values })
+    }
+}
+
+/// The builtins available to the shell, by name.
+pub struct Registry {
+    builtins: HashMap<&'static str, Rc<dyn Builtin>>,
+}
+
+impl Registry {
+    /// A registry with all of the shell's own builtins.
+    pub fn new() -> Registry {
+        let mut registry = Registry { builtins: HashMap::new() };
+        registry.add(ABBR, builtin_abbr);
+        registry.add(ALIAS, builtin_alias);
+        registry.add(BG, builtin_bg);
+        registry.add(BREAK, builtin_break);
+        registry.add(CD, builtin_cd);
+        registry.add(CONTINUE, builtin_continue);
+        registry.add(DIRS, builtin_dirs);
+        registry.add(DISOWN, builtin_disown);
+        registry.add(EXIT, builtin_exit);
+        registry.add(EXPORT, builtin_export);
+        registry.add(FG, builtin_fg);
+        registry.add(HELP, builtin_help);
+        registry.add(J, builtin_j);
+        registry.add(JOBS, builtin_jobs);
+        registry.add(LET, builtin_let);
+        registry.add(NEXTD, builtin_nextd);
+        registry.add(POPD, builtin_popd);
+        registry.add(PREVD, builtin_prevd);
+        registry.add(PUSHD, builtin_pushd);
+        registry.add(RETURN, builtin_return);
+        registry.add(SET, builtin_set);
+        registry.add(SOURCE, builtin_source);
+        registry.add(UNALIAS, builtin_unalias);
+        registry.add(UNSET, builtin_unset);
+        registry.add(WAIT, builtin_wait);
+        registry.add(Z, builtin_z);
+        registry
+    }
+
+    fn add(&mut self, info: Info, func: fn(&mut Context, &Args) -> i32) {
+        self.register(Rc::new(FnBuiltin { info, func }));
+    }
+
+    /// Add a builtin, replacing any other with the same name.
+    pub fn register(&mut self, builtin: Rc<dyn Builtin>) {
+        self.builtins.insert(builtin.info().name, builtin);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Rc<dyn Builtin>> {
+        self.builtins.get(name).cloned()
+    }
+
+    /// The names of every builtin, sorted.
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names: Vec<&'static str> = self.builtins.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// The flags of the builtin called `name`, if there is one.
+    pub fn flags(&self, name: &str) -> &'static [Flag] {
+        self.builtins.get(name).map_or(&[], |builtin| builtin.info().flags)
+    }
+}
+
+/// The usage line for a builtin, like `set [-x] [name [value...]]`.
+fn usage(info: &Info) -> String {
+    if info.usage.is_empty() {
+        String::from(info.name)
+    } else {
+        format!("{} {}", info.name, info.usage)
+    }
+}
+
+/// The full description of a builtin shown by `help`, with its usage, flags and examples.
+fn describe(info: &Info) -> String {
+    let mut text = format!("usage: {}\n\n{}\n", usage(info), info.description);
+    if !info.details.is_empty() {
+        text.push_str(&format!("\n{}\n", info.details));
+    }
+
+    if !info.flags.is_empty() {
+        text.push_str("\nFlags:\n");
+        for flag in info.flags {
+            text.push_str(&format!("  {}  {}\n", flag.name, flag.description));
+        }
+    }
+
+    if !info.examples.is_empty() {
+        text.push_str("\nExamples:\n");
+        for example in info.examples {
+            text.push_str(&format!("  {}\n", example));
+        }
+    }
+    text
+}
+
+/// Check a builtin's arguments and run it, reporting bad arguments on `streams`. Every builtin
+/// takes `--help` as its first argument to describe itself instead.
+pub fn run(shell: &mut Shell, builtin: &dyn Builtin, args: &[String], streams: &mut Streams)
+           -> i32 {
+    let info = builtin.info();
+    if args.first().is_some_and(|arg| arg == "--help") {
+        let _ = write!(streams.stdout, "{}", describe(info));
+        return 0;
+    }
+
+    match parse_args(info, args) {
+        Ok(args) => builtin.run(&mut Context { shell, streams }, &args),
+        Err(e) => {
+            let _ = writeln!(streams.stderr, "shroom: {}: {}", info.name, e);
+            let _ = writeln!(streams.stderr, "usage: {}", usage(info));
+            1
+        },
+    }
+}
+
+/// Change to `target` for `cmd`, reporting any error, and record the visit for `z` unless it's
+/// to the home directory. Returns the old directory.
+fn change_dir(ctx: &mut Context, cmd: &'static str, target: &Path, physical: bool)
+              -> Option<PathBuf> {
+    let old = match dirs::change_dir(target, physical, &mut ctx.shell.variables) {
+        Ok(old) => old,
+        Err(e) => {
+            let _ = writeln!(ctx.streams.stderr, "shroom: {}: {}: {}", cmd, target.display(), e);
+            return None;
+        },
+    };
+
+    let pwd = ctx.shell.variables.get("PWD").unwrap_or_default().join(" ");
+    if Some(&pwd) != tilde::home_dir(&ctx.shell.variables).as_ref() {
+        if let Err(e) = ctx.shell.visited_dirs.visit(Path::new(&pwd)) {
+            let _ = writeln!(ctx.streams.stderr, "shroom: {}: can't save directories: {}", cmd, e);
+        }
+    }
+    Some(old)
+}
+
+/// Find the directory `cd` or `pushd` should go to for an argument: `-` is `$OLDPWD`, and a
+/// relative path can be found through `$CDPATH`. Also returns whether the directory should be
+/// printed, since it isn't the one given.
+fn resolve_dir(ctx: &mut Context, cmd: &'static str, arg: &str) -> Option<(PathBuf, bool)> {
+    if arg == "-" {
+        return match ctx.shell.variables.get("OLDPWD") {
+            Some(ref dir) if !dir.is_empty() => Some((PathBuf::from(dir.join(" ")), true)),
+            _ => {
+                let _ = writeln!(ctx.streams.stderr, "shroom: {}: $OLDPWD isn't set", cmd);
+                None
+            },
+        };
+    }
+
+    match dirs::search_cdpath(arg, &ctx.shell.variables) {
+        Some(dir) => {
+            let print = dir != Path::new(arg);
+            Some((dir, print))
+        },
+        None => Some((PathBuf::from(arg), false)),
+    }
+}
+
+fn print_pwd(ctx: &mut Context) {
+    let pwd = ctx.shell.variables.get("PWD").unwrap_or_default().join(" ");
+    let _ = writeln!(ctx.streams.stdout, "{}", pwd);
+}
+
+const LOGICAL_FLAGS: &[Flag] = &[
+    Flag { name: "-L", description: "Keep symlinks in $PWD, and go back through them with `..`." },
+    Flag { name: "-P", description: "Resolve symlinks, so $PWD is the real path." },
+];
+
+const CD: Info = Info {
+    name: "cd",
+    usage: "[-L | -P] [dir | -]",
+    description: "Change the current directory, to the home directory by default.",
+    details: "`cd -` changes to the previous directory, $OLDPWD. A relative directory which \
+              doesn't start with `.` is looked for in each directory in $CDPATH, where an empty \
+              entry is the current directory. Paths are logical by default, as with `-L`, so \
+              after `cd /link/dir`, `cd ..` goes to `/link` even if `/link` is a symlink.",
+    flags: LOGICAL_FLAGS,
+    examples: &["cd /tmp", "cd -", "cd -P /link/dir", "cd"],
+    min_args: 0,
+    max_args: 1,
+};
+
+fn builtin_cd(ctx: &mut Context, args: &Args) -> i32 {
+    let (target, print) = match args.values.first() {
+        Some(arg) => match resolve_dir(ctx, "cd", arg) {
+            Some(resolved) => resolved,
+            None => return 1,
+        },
+        None => match tilde::home_dir(&ctx.shell.variables) {
+            Some(home) => (PathBuf::from(home), false),
+            None => {
+                let _ = writeln!(ctx.streams.stderr, "shroom: cd: couldn't find home dir");
+                return 1;
+            },
+        },
+    };
+
+    let old = match change_dir(ctx, "cd", &target, args.has_flag("-P")) {
+        Some(old) => old,
+        None => return 1,
+    };
+    ctx.shell.dirs.record(old);
+    if print {
+        print_pwd(ctx);
+    }
+    0
+}
+
+const PUSHD: Info = Info {
+    name: "pushd",
+    usage: "[-L | -P] [dir]",
+    description: "Save the current directory on the directory stack and change to another.",
+    details: "With no directory, swaps the current directory with the one on top of the stack.",
+    flags: LOGICAL_FLAGS,
+    examples: &["pushd /tmp", "pushd"],
+    min_args: 0,
+    max_args: 1,
+};
+
+fn builtin_pushd(ctx: &mut Context, args: &Args) -> i32 {
+    let physical = args.has_flag("-P");
+    let (target, print) = match args.values.first() {
+        Some(arg) => match resolve_dir(ctx, "pushd", arg) {
+            Some(resolved) => resolved,
+            None => return 1,
+        },
+        None => match ctx.shell.dirs.stack.pop() {
+            Some(top) => (top, false),
+            None => {
+                let _ = writeln!(ctx.streams.stderr, "shroom: pushd: no other directory");
+                return 1;
+            },
+        },
+    };
+
+    match change_dir(ctx, "pushd", &target, physical) {
+        Some(old) => {
+            ctx.shell.dirs.stack.push(old.clone());
+            ctx.shell.dirs.record(old);
+        },
+        None => {
+            // Put back the directory which was swapped with.
+            if args.values.is_empty() {
+                ctx.shell.dirs.stack.push(target);
+            }
+            return 1;
+        },
+    }
+    if print {
+        print_pwd(ctx);
+    }
+    0
+}
+
+const POPD: Info = Info {
+    name: "popd",
+    usage: "[-L | -P]",
+    description: "Change to the directory on top of the directory stack, removing it.",
+    details: "",
+    flags: LOGICAL_FLAGS,
+    examples: &[],
+    min_args: 0,
+    max_args: 0,
+};
+
+fn builtin_popd(ctx: &mut Context, args: &Args) -> i32 {
+    let target = match ctx.shell.dirs.stack.pop() {
+        Some(top) => top,
+        None => {
+            let _ = writeln!(ctx.streams.stderr, "shroom: popd: directory stack is empty");
+            return 1;
+        },
+    };
+
+    match change_dir(ctx, "popd", &target, args.has_flag("-P")) {
+        Some(old) => {
+            ctx.shell.dirs.record(old);
+            0
+        },
+        None => {
+            ctx.shell.dirs.stack.push(target);
+            1
+        },
+    }
+}
+
+const DIRS: Info = Info {
+    name: "dirs",
+    usage: "[-c]",
+    description: "Show the directory stack, starting with the current directory.",
+    details: "",
+    flags: &[
+        Flag { name: "-c", description: "Clear the directory stack." },
+    ],
+    examples: &[],
+    min_args: 0,
+    max_args: 0,
+};
+
+fn builtin_dirs(ctx: &mut Context, args: &Args) -> i32 {
+    if args.has_flag("-c") {
+        ctx.shell.dirs.stack.clear();
+        return 0;
+    }
+
+    let current = match dirs::logical_cwd(&ctx.shell.variables) {
+        Ok(current) => current,
+        Err(e) => {
+            let _ = writeln!(ctx.streams.stderr, "shroom: dirs: {}", e);
+            return 1;
+        },
+    };
+    let variables = &ctx.shell.variables;
+    let dirs: Vec<String> = Some(current).iter().chain(ctx.shell.dirs.stack.iter().rev())
+        .map(|dir| dirs::display(dir, variables))
+        .collect();
+    let _ = writeln!(ctx.streams.stdout, "{}", dirs.join(" "));
+    0
+}
+
+const HISTORY_FLAGS: &[Flag] = &[
+    Flag { name: "-l", description: "List the directory history." },
+];
+
+const PREVD: Info = Info {
+    name: "prevd",
+    usage: "[-l] [count]",
+    description: "Go back through the directories changed to, one by default.",
+    details: "Directories changed to with `cd`, `pushd`, `popd` and `z` are remembered, up to \
+              25.",
+    flags: HISTORY_FLAGS,
+    examples: &["prevd", "prevd 2", "prevd -l"],
+    min_args: 0,
+    max_args: 1,
+};
+
+fn builtin_prevd(ctx: &mut Context, args: &Args) -> i32 {
+    move_through_history(ctx, "prevd", args, false)
+}
+
+const NEXTD: Info = Info {
+    name: "nextd",
+    usage: "[-l] [count]",
+    description: "Go forward through the directories `prevd` went back from, one by default.",
+    details: "",
+    flags: HISTORY_FLAGS,
+    examples: &["nextd", "nextd -l"],
+    min_args: 0,
+    max_args: 1,
+};
+
+fn builtin_nextd(ctx: &mut Context, args: &Args) -> i32 {
+    move_through_history(ctx, "nextd", args, true)
+}
+
+/// Move `forward` or back through the directory history, for `nextd` and `prevd`.
+fn move_through_history(ctx: &mut Context, cmd: &'static str, args: &Args, forward: bool)
+                        -> i32 {
+    if args.has_flag("-l") {
+        let current = match dirs::logical_cwd(&ctx.shell.variables) {
+            Ok(current) => current,
+            Err(e) => {
+                let _ = writeln!(ctx.streams.stderr, "shroom: {}: {}", cmd, e);
+                return 1;
+            },
+        };
+        let (dirs, current_index) = ctx.shell.dirs.list_history(current);
+        let dirs: Vec<String> = dirs.iter().enumerate().map(|(i, dir)| {
+            let dir = dirs::display(dir, &ctx.shell.variables);
+            if i == current_index { format!("[{}]", dir) } else { dir }
+        }).collect();
+        let _ = writeln!(ctx.streams.stdout, "{}", dirs.join(" "));
+        return 0;
+    }
+
+    let count = match args.values.first().map(|count| count.parse::<usize>()) {
+        None => 1,
+        Some(Ok(count)) => count,
+        Some(Err(e)) => {
+            let _ = writeln!(ctx.streams.stderr, "shroom: {}: can't parse count: {}", cmd, e);
+            return 1;
+        },
+    };
+
+    let target = match ctx.shell.dirs.history(forward, count) {
+        Some(target) => target.to_path_buf(),
+        None => {
+            let which = if forward { "next" } else { "previous" };
+            let _ = writeln!(ctx.streams.stderr, "shroom: {}: no {} directory", cmd, which);
+            return 1;
+        },
+    };
+
+    match change_dir(ctx, cmd, &target, false) {
+        Some(old) => {
+            ctx.shell.dirs.step(forward, count, old);
+            0
+        },
+        None => 1,
+    }
+}
+
+const JUMP_FLAGS: &[Flag] = &[
+    Flag { name: "-i", description: "Choose from the matching directories." },
+    Flag { name: "-l", description: "List the matching directories with their scores." },
+];
+
+const Z: Info = Info {
+    name: "z",
+    usage: "[-i | -l] [term...]",
+    description: "Jump to the most frecent directory matching all the terms.",
+    details: "Directories changed to in interactive shells are remembered, except the home \
+              directory, and ranked by frecency: how often they're visited, weighted towards \
+              recent visits. A directory matches if the terms appear in its path in order, with \
+              the last term in its last component. Case only matters if some directory matches \
+              with it. When no directory clearly scores best and stdin is a terminal, the \
+              matches are listed to choose from. With no terms, lists every directory.",
+    flags: JUMP_FLAGS,
+    examples: &["z shroom", "z src shroom", "z -l rs", "z -i proj"],
+    min_args: 0,
+    max_args: usize::MAX,
+};
+
+fn builtin_z(ctx: &mut Context, args: &Args) -> i32 {
+    jump(ctx, "z", args)
+}
+
+const J: Info = Info {
+    name: "j",
+    description: "Same as `z`.",
+    examples: &["j shroom"],
+    ..Z
+};
+
+fn builtin_j(ctx: &mut Context, args: &Args) -> i32 {
+    jump(ctx, "j", args)
+}
+
+/// The most directories listed to choose from when a jump is ambiguous.
+const MAX_CHOICES: usize = 9;
+
+/// Jump to the directory best matching the terms in `args`, for `z` and `j`. The best match
+/// is only taken as is if it scores at least twice as high as the next.
+fn jump(ctx: &mut Context, cmd: &'static str, args: &Args) -> i32 {
+    let found: Vec<(String, f64)> = ctx.shell.visited_dirs.search(args.values).into_iter()
+        .map(|(entry, score)| (entry.path.clone(), score))
+        .collect();
+    if found.is_empty() {
+        let _ = writeln!(ctx.streams.stderr, "shroom: {}: no matching directory", cmd);
+        return 1;
+    }
+
+    if args.has_flag("-l") || args.values.is_empty() {
+        for &(ref path, score) in &found {
+            let path = dirs::display(Path::new(path), &ctx.shell.variables);
+            let _ = writeln!(ctx.streams.stdout, "{:>8.1}  {}", score, path);
+        }
+        return 0;
+    }
+
+    let ambiguous = found.len() > 1 && (args.has_flag("-i") || found[0].1 < found[1].1 * 2.0);
+    let target = if ambiguous && ctx.streams.stdin.is_terminal() {
+        match choose_dir(ctx, cmd, &found) {
+            Some(target) => target,
+            None => return 1,
+        }
+    } else {
+        found[0].0.clone()
+    };
+
+    match change_dir(ctx, cmd, Path::new(&target), false) {
+        Some(old) => {
+            ctx.shell.dirs.record(old);
+            0
+        },
+        None => 1,
+    }
+}
+
+/// List the best matching directories on stderr and read the number of one from stdin.
+/// Returns `None` if nothing valid was chosen.
+fn choose_dir(ctx: &mut Context, cmd: &'static str, found: &[(String, f64)]) -> Option<String> {
+    let choices = &found[..found.len().min(MAX_CHOICES)];
+    for (i, (path, _)) in choices.iter().enumerate() {
+        let path = dirs::display(Path::new(path), &ctx.shell.variables);
+        let _ = writeln!(ctx.streams.stderr, "{}  {}", i + 1, path);
+    }
+    let _ = write!(ctx.streams.stderr, "{}: choose a directory [1-{}]: ", cmd, choices.len());
+
+    let mut line = String::new();
+    if let Err(e) = BufReader::new(&ctx.streams.stdin).read_line(&mut line) {
+        let _ = writeln!(ctx.streams.stderr, "shroom: {}: {}", cmd, e);
+        return None;
+    }
+    match line.trim().parse::<usize>() {
+        Ok(choice) if 1 <= choice && choice <= choices.len() => {
+            Some(choices[choice - 1].0.clone())
+        },
+        _ => None,
+    }
+}
+
+const EXIT: Info = Info {
+    name: "exit",
+    usage: "[exit_code]",
+    description: "Exit the shell.",
+    details: "The exit code defaults to 0. In a command substitution, only the substitution is \
+              ended, and the shell carries on.",
+    flags: &[],
+    examples: &["exit 1"],
+    min_args: 0,
+    max_args: 1,
+};
+
+fn builtin_exit(ctx: &mut Context, args: &Args) -> i32 {
+    let exit_code = match args.values.first() {
+        Some(exit_code) => match exit_code.parse() {
+            Ok(exit_code) => exit_code,
+            Err(e) => {
+                let _ = writeln!(ctx.streams.stderr, "shroom: exit: can't parse exit code: {}", e);
+                return 1;
+            },
+        },
+        None => 0,
+    };
+
+    if ctx.shell.capture_depth > 0 {
+        ctx.shell.control = Some(Control::Exit);
+        return exit_code;
+    }
+    std::process::exit(exit_code);
+}
+
+/// Check that a variable name given to a builtin is valid, reporting an error if it isn't.
+fn check_variable_name(cmd: &'static str, name: &str, streams: &mut Streams) -> bool {
+    if !variables::is_valid_name(name) {
+        let _ = writeln!(streams.stderr, "shroom: {}: invalid variable name: {}", cmd, name);
+        false
+    } else if variables::is_read_only(name) {
+        let _ = writeln!(streams.stderr, "shroom: {}: {} is read-only", cmd, name);
+        false
+    } else {
+        true
+    }
+}
+
+const SET: Info = Info {
+    name: "set",
+    usage: "[-x] [name [value...]]",
+    description: "Assign to a variable, or list all variables.",
+    details: "The values are assigned to the innermost variable with this name, or else to a new \
+              global. With just a name, the variable is set to an empty list. Setting \
+              $status_message changes the message printed when a command fails at the prompt, \
+              where `{status}`, `{pipestatus}` and `{reason}` are replaced, and an empty \
+              message turns it off.",
+    flags: &[
+        Flag { name: "-x", description: "Also export the variable." },
+    ],
+    examples: &["set path /usr/bin /bin", "set -x EDITOR vim",
+                "set status_message '[{pipestatus}] {reason}'"],
+    min_args: 0,
+    max_args: usize::MAX,
+};
+
+fn builtin_set(ctx: &mut Context, args: &Args) -> i32 {
+    match args.values.split_first() {
+        None => {
+            for (name, var) in ctx.shell.variables.all() {
+                let _ = writeln!(ctx.streams.stdout, "{} {}", name, var.value.join(" "));
+            }
+            0
+        },
+
+        Some((name, values)) => {
+            if !check_variable_name("set", name, ctx.streams) {
+                return 1;
+            }
+            ctx.shell.variables.set(name, values.to_vec());
+            if args.has_flag("-x") {
+                ctx.shell.variables.export(name);
+            }
+            0
+        },
+    }
+}
+
+const LET: Info = Info {
+    name: "let",
+    usage: "name [value...]",
+    description: "Create a variable in the innermost scope.",
+    details: "The variable shadows any variable with the same name from outer scopes, until the \
+              function it's in returns.",
+    flags: &[],
+    examples: &["let count 0"],
+    min_args: 1,
+    max_args: usize::MAX,
+};
+
+fn builtin_let(ctx: &mut Context, args: &Args) -> i32 {
+    let (name, values) = args.values.split_first().unwrap();
+    if !check_variable_name("let", name, ctx.streams) {
+        return 1;
+    }
+    ctx.shell.variables.set_local(name, values.to_vec());
+    0
+}
+
+const EXPORT: Info = Info {
+    name: "export",
+    usage: "[name [value...]]",
+    description: "Export a variable to child processes, or list the exported variables.",
+    details: "",
+    flags: &[],
+    examples: &["export EDITOR vim", "export PATH"],
+    min_args: 0,
+    max_args: usize::MAX,
+};
+
+fn builtin_export(ctx: &mut Context, args: &Args) -> i32 {
+    match args.values.split_first() {
+        None => {
+            for (name, value) in ctx.shell.variables.exported() {
+                let _ = writeln!(ctx.streams.stdout, "{} {}", name, value);
+            }
+            0
+        },
+
+        Some((name, values)) => {
+            if !check_variable_name("export", name, ctx.streams) {
+                return 1;
+            }
+            if !values.is_empty() {
+                ctx.shell.variables.set(name, values.to_vec());
+            }
+            ctx.shell.variables.export(name);
+            0
+        },
+    }
+}
+
+const UNSET: Info = Info {
+    name: "unset",
+    usage: "name...",
+    description: "Remove variables, failing if any of them weren't set.",
+    details: "",
+    flags: &[],
+    examples: &["unset tmp"],
+    min_args: 1,
+    max_args: usize::MAX,
+};
+
+fn builtin_unset(ctx: &mut Context, args: &Args) -> i32 {
+    let mut exit_code = 0;
+    for name in args.values {
+        if !check_variable_name("unset", name, ctx.streams) || !ctx.shell.variables.unset(name) {
+            exit_code = 1;
+        }
+    }
+    exit_code
+}
+
+/// Set `control` for the innermost loop, if there is one.
+fn loop_control(shell: &mut Shell, cmd: &'static str, control: Control,
+                streams: &mut Streams) -> i32 {
+    if shell.loop_depth == 0 {
+        let _ = writeln!(streams.stderr, "shroom: {}: not inside of a loop", cmd);
+        return 1;
+    }
+    shell.control = Some(control);
+    0
+}
+
+const BREAK: Info = Info {
+    name: "break",
+    usage: "",
+    description: "Stop the innermost loop.",
+    details: "",
+    flags: &[],
+    examples: &["for x in *; if test -d $x; break; end; end"],
+    min_args: 0,
+    max_args: 0,
+};
+
+fn builtin_break(ctx: &mut Context, _args: &Args) -> i32 {
+    loop_control(ctx.shell, "break", Control::Break, ctx.streams)
+}
+
+const CONTINUE: Info = Info {
+    name: "continue",
+    usage: "",
+    description: "Skip to the next iteration of the innermost loop.",
+    details: "",
+    flags: &[],
+    examples: &["for x in *; if test -d $x; continue; end; echo $x; end"],
+    min_args: 0,
+    max_args: 0,
+};
+
+fn builtin_continue(ctx: &mut Context, _args: &Args) -> i32 {
+    loop_control(ctx.shell, "continue", Control::Continue, ctx.streams)
+}
+
+const RETURN: Info = Info {
+    name: "return",
+    usage: "[exit_code]",
+    description: "Stop the function being run, with the exit code of the last command by default.",
+    details: "",
+    flags: &[],
+    examples: &["return 1"],
+    min_args: 0,
+    max_args: 1,
+};
+
+fn builtin_return(ctx: &mut Context, args: &Args) -> i32 {
+    if ctx.shell.function_depth == 0 {
+        let _ = writeln!(ctx.streams.stderr, "shroom: return: not inside of a function");
+        return 1;
+    }
+
+    let exit_code = match args.values.first() {
+        Some(exit_code) => match exit_code.parse() {
+            Ok(exit_code) => exit_code,
+            Err(e) => {
+                let _ = writeln!(ctx.streams.stderr, "shroom: return: can't parse exit code: {}",
+                                 e);
+                return 1;
+            },
+        },
+        None => {
+            let status = ctx.shell.variables.get("status").unwrap_or_default();
+            status.first().and_then(|status| status.parse().ok()).unwrap_or(0)
+        },
+    };
+    ctx.shell.control = Some(Control::Return);
+    exit_code
+}
+
+/// Whether `name` can be used for an alias or abbreviation, which means it must be a word
+/// which doesn't need quoting.
+fn check_alias_name(cmd: &'static str, name: &str, streams: &mut Streams) -> bool {
+    let valid = !name.is_empty() &&
+                name.chars().all(|c| Lexer::is_unquoted_text(c) && c != '/' && c != '=');
+    if !valid {
+        let _ = writeln!(streams.stderr, "shroom: {}: invalid name: {}", cmd, name);
+    }
+    valid
+}
+
+/// Print `name` and `value` in the form the `cmd` builtin accepts, like `alias ll = ls -la`.
+fn print_definitions(cmd: &'static str, definitions: &HashMap<String, String>,
+                     names: &[String], streams: &mut Streams) -> i32 {
+    let mut exit_code = 0;
+    for name in names {
+        match definitions.get(name) {
+            Some(value) => {
+                let _ = writeln!(streams.stdout, "{} {} = {}", cmd, name, value);
+            },
+            None => {
+                let _ = writeln!(streams.stderr, "shroom: {}: no such {}: {}", cmd, cmd, name);
+                exit_code = 1;
+            },
+        }
+    }
+    exit_code
+}
+
+/// Split the arguments of `alias` or `abbr` into the name and the value after an optional `=`.
+fn split_definition(args: &[String]) -> Option<(&String, String)> {
+    let (name, value) = args.split_first()?;
+    let value = match value.split_first() {
+        Some((equals, value)) if equals == "=" => value,
+        _ => value,
+    };
+    if value.is_empty() { None } else { Some((name, value.join(" "))) }
+}
+
+const ALIAS: Info = Info {
+    name: "alias",
+    usage: "[name [= command...]]",
+    description: "Make a name stand for a command, or show aliases.",
+    details: "Any arguments the alias is called with are added to the end of the command. An \
+              alias isn't expanded inside itself, so an alias can add arguments to the command \
+              with the same name.",
+    flags: &[],
+    examples: &["alias ll = ls -la", "alias ls = ls --color=auto", "alias"],
+    min_args: 0,
+    max_args: usize::MAX,
+};
+
+fn builtin_alias(ctx: &mut Context, args: &Args) -> i32 {
+    let (name, value) = match split_definition(args.values) {
+        Some(definition) => definition,
+        None => {
+            let mut names: Vec<String> = match args.values.first() {
+                Some(name) => vec![name.clone()],
+                None => ctx.shell.aliases.keys().cloned().collect(),
+            };
+            names.sort();
+            return print_definitions("alias", &ctx.shell.aliases, &names, ctx.streams);
+        },
+    };
+
+    if !check_alias_name("alias", name, ctx.streams) {
+        return 1;
+    }
+
+    match Parser::new(&value).parse() {
+        Ok(Ast::Call { .. }) => {},
+        Ok(_) => {
+            let _ = writeln!(ctx.streams.stderr, "shroom: alias: {} must be a single command",
+                             name);
+            return 1;
+        },
+        Err(e) => {
+            let _ = writeln!(ctx.streams.stderr, "shroom: alias: {}", e.render(&value, None, 1));
+            return 1;
+        },
+    }
+
+    ctx.shell.aliases.insert(name.clone(), value);
+    0
+}
+
+const UNALIAS: Info = Info {
+    name: "unalias",
+    usage: "name...",
+    description: "Remove aliases.",
+    details: "",
+    flags: &[],
+    examples: &["unalias ll"],
+    min_args: 1,
+    max_args: usize::MAX,
+};
+
+fn builtin_unalias(ctx: &mut Context, args: &Args) -> i32 {
+    let mut exit_code = 0;
+    for name in args.values {
+        if ctx.shell.aliases.remove(name).is_none() {
+            let _ = writeln!(ctx.streams.stderr, "shroom: unalias: no such alias: {}", name);
+            exit_code = 1;
+        }
+    }
+    exit_code
+}
+
+const ABBR: Info = Info {
+    name: "abbr",
+    usage: "[-e] [name [= text...]]",
+    description: "Add an abbreviation which is expanded as it's typed, or show abbreviations.",
+    details: "An abbreviation is replaced with its text in the line editor when it's typed as a \
+              command followed by a space or Enter, so history has the full command.",
+    flags: &[
+        Flag { name: "-e", description: "Remove the named abbreviations." },
+    ],
+    examples: &["abbr gco = git checkout", "abbr -e gco"],
+    min_args: 0,
+    max_args: usize::MAX,
+};
+
+fn builtin_abbr(ctx: &mut Context, args: &Args) -> i32 {
+    if args.has_flag("-e") {
+        let mut exit_code = 0;
+        for name in args.values {
+            if ctx.shell.abbreviations.remove(name).is_none() {
+                let _ = writeln!(ctx.streams.stderr, "shroom: abbr: no such abbr: {}", name);
+                exit_code = 1;
+            }
+        }
+        return exit_code;
+    }
+
+    match split_definition(args.values) {
+        Some((name, value)) => {
+            if !check_alias_name("abbr", name, ctx.streams) {
+                return 1;
+            }
+            ctx.shell.abbreviations.insert(name.clone(), value);
+            0
+        },
+        None => {
+            let mut names: Vec<String> = match args.values.first() {
+                Some(name) => vec![name.clone()],
+                None => ctx.shell.abbreviations.keys().cloned().collect(),
+            };
+            names.sort();
+            print_definitions("abbr", &ctx.shell.abbreviations, &names, ctx.streams)
+        },
+    }
+}
+
+const SOURCE: Info = Info {
+    name: "source",
+    usage: "path [arg...]",
+    description: "Run the commands in a file in the current shell.",
+    details: "Variables created with `let` are local to the file, and $argv holds the arguments.",
+    flags: &[],
+    examples: &["source ~/.config/shroom/extra.shr"],
+    min_args: 1,
+    max_args: usize::MAX,
+};
+
+fn builtin_source(ctx: &mut Context, args: &Args) -> i32 {
+    let (path, source_args) = args.values.split_first().unwrap();
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            let _ = writeln!(ctx.streams.stderr, "shroom: source: {}: {}", path, e);
+            return 1;
+        },
+    };
+
+    let ast = match Parser::new(&source).parse() {
+        Ok(ast) => ast,
+        Err(e) => {
+            let _ = writeln!(ctx.streams.stderr, "shroom: {}", e.render(&source, Some(path), 1));
+            return 2;
+        },
+    };
+
+    let _ = io::stdout().flush();
+    let saved = match with_streams(ctx.streams) {
+        Ok(saved) => saved,
+        Err(e) => {
+            let _ = writeln!(ctx.streams.stderr, "shroom: source: {}", e);
+            return 1;
+        },
+    };
+
+    ctx.shell.variables.push_scope();
+    ctx.shell.variables.set_local("argv", source_args.to_vec());
+    let exit_code = execute(ctx.shell, &ast);
+    ctx.shell.variables.pop_scope();
+
+    let _ = io::stdout().flush();
+    restore_streams(saved);
+    exit_code
+}
+
+/// Find the job named by a job spec argument, or the current job if there's no argument.
+fn find_job(shell: &Shell, cmd: &'static str, args: &[String], streams: &mut Streams)
+            -> Option<usize> {
+    let spec = args.first().map(|arg| &arg[..]);
+    let index = shell.jobs.find(spec);
+    if index.is_none() {
+        match spec {
+            Some(spec) => {
+                let _ = writeln!(streams.stderr, "shroom: {}: no such job: {}", cmd, spec);
+            },
+            None => {
+                let _ = writeln!(streams.stderr, "shroom: {}: no current job", cmd);
+            },
+        }
+    }
+    index
+}
+
+const JOBS: Info = Info {
+    name: "jobs",
+    usage: "",
+    description: "List the background and stopped jobs.",
+    details: "",
+    flags: &[],
+    examples: &[],
+    min_args: 0,
+    max_args: 0,
+};
+
+fn builtin_jobs(ctx: &mut Context, _args: &Args) -> i32 {
+    for line in ctx.shell.jobs.list() {
+        let _ = writeln!(ctx.streams.stdout, "{}", line);
+    }
+    0
+}
+
+const FG: Info = Info {
+    name: "fg",
+    usage: "[job]",
+    description: "Bring a job to the foreground, resuming it if it was stopped.",
+    details: "Jobs are named like `%1`, and the current job is the most recently started or \
+              stopped one.",
+    flags: &[],
+    examples: &["fg", "fg %2"],
+    min_args: 0,
+    max_args: 1,
+};
+
+fn builtin_fg(ctx: &mut Context, args: &Args) -> i32 {
+    let index = match find_job(ctx.shell, "fg", args.values, ctx.streams) {
+        Some(index) => index,
+        None => return 1,
+    };
+
+    let job = ctx.shell.jobs.remove(index);
+    let _ = writeln!(ctx.streams.stderr, "{}", job.command);
+    let job = ctx.shell.jobs.run_foreground(job, true);
+    ctx.shell.last_termination = job.termination();
+    job.exit_code()
+}
+
+const BG: Info = Info {
+    name: "bg",
+    usage: "[job]",
+    description: "Resume a stopped job in the background.",
+    details: "",
+    flags: &[],
+    examples: &["bg %1"],
+    min_args: 0,
+    max_args: 1,
+};
+
+fn builtin_bg(ctx: &mut Context, args: &Args) -> i32 {
+    let index = match find_job(ctx.shell, "bg", args.values, ctx.streams) {
+        Some(index) => index,
+        None => return 1,
+    };
+
+    match ctx.shell.jobs.resume_background(index) {
+        Ok(()) => {
+            let lines = ctx.shell.jobs.list();
+            let _ = writeln!(ctx.streams.stderr, "{}", lines.last().unwrap());
+            0
+        },
+        Err(e) => {
+            let _ = writeln!(ctx.streams.stderr, "shroom: bg: {}", e);
+            1
+        },
+    }
+}
+
+const WAIT: Info = Info {
+    name: "wait",
+    usage: "[job...]",
+    description: "Wait for jobs to finish or stop, or all of them by default.",
+    details: "The exit code is the last job's.",
+    flags: &[],
+    examples: &["wait", "wait %1 %2"],
+    min_args: 0,
+    max_args: usize::MAX,
+};
+
+fn builtin_wait(ctx: &mut Context, args: &Args) -> i32 {
+    if args.values.is_empty() {
+        let mut exit_code = 0;
+        while let Some(index) = ctx.shell.jobs.find(None) {
+            let job = ctx.shell.jobs.wait(index);
+            exit_code = job.exit_code();
+            if job.is_stopped() {
+                break;
+            }
+        }
+        return exit_code;
+    }
+
+    let mut exit_code = 0;
+    for arg in args.values {
+        exit_code = match find_job(ctx.shell, "wait", std::slice::from_ref(arg), ctx.streams) {
+            Some(index) => ctx.shell.jobs.wait(index).exit_code(),
+            None => 127,
+        };
+    }
+    exit_code
+}
+
+const DISOWN: Info = Info {
+    name: "disown",
+    usage: "[job]",
+    description: "Remove a job from the job table, leaving it running.",
+    details: "",
+    flags: &[],
+    examples: &["disown %1"],
+    min_args: 0,
+    max_args: 1,
+};
+
+fn builtin_disown(ctx: &mut Context, args: &Args) -> i32 {
+    match find_job(ctx.shell, "disown", args.values, ctx.streams) {
+        Some(index) => {
+            ctx.shell.jobs.remove(index);
+            0
+        },
+        None => 1,
+    }
+}
+
+const HELP: Info = Info {
+    name: "help",
+    usage: "[builtin]",
+    description: "List the builtins, or describe one.",
+    details: "`builtin --help` describes a builtin too.",
+    flags: &[],
+    examples: &["help", "help set", "set --help"],
+    min_args: 0,
+    max_args: 1,
+};
+
+fn builtin_help(ctx: &mut Context, args: &Args) -> i32 {
+    if let Some(name) = args.values.first() {
+        return match ctx.shell.builtins.get(name) {
+            Some(builtin) => {
+                let _ = write!(ctx.streams.stdout, "{}", describe(builtin.info()));
+                0
+            },
+            None => {
+                let _ = writeln!(ctx.streams.stderr, "shroom: help: no such builtin: {}", name);
+                1
+            },
+        };
+    }
+
+    let names = ctx.shell.builtins.names();
+    let width = names.iter().map(|name| name.len()).max().unwrap_or(0);
+    for name in names {
+        let builtin = ctx.shell.builtins.get(name).unwrap();
+        let _ = writeln!(ctx.streams.stdout, "{:2$}  {}", name, builtin.info().description, width);
+    }
+    0
+}
@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use builtins::Registry;
+use editor::Completions;
+use parser::{Expr, Lexer, Token};
+use tilde;
+use variables::Variables;
+
+/// The kind of quoting still open at the cursor.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Quote {
+    None,
+    Single,
+    Double,
+}
+
+/// The word the cursor is at the end of, found by lexing the line before the cursor.
+struct Word {
+    /// The word's value with quotes and escapes removed.
+    value: String,
+
+    /// Whether the word is the name of the command to run.
+    is_command: bool,
+
+    /// The name of the command the word is an argument to, if it isn't the command itself.
+    command: String,
+
+    quote: Quote,
+
+    /// The variable name being typed at the end of the word after `$` or `${`, and whether
+    /// it's in braces.
+    variable: Option<(String, bool)>,
+}
+
+/// A name added to finish a variable name which is still empty, like `$` or `${`.
+const PLACEHOLDER: &str = "_";
+
+/// If `tokens`, lexed from `line` followed by `ending` and maybe a closing quote, end with a
+/// variable whose name was still being typed, return the name typed so far and whether it's in
+/// braces. A name followed by anything else in the line, like a `}` or a quote, is finished.
+fn typed_variable(line: &str, tokens: &[Token], ending: &str) -> Option<(String, bool)> {
+    let name = match tokens.last()? {
+        Token::Variable(name) => name,
+        Token::Quoted(parts) => match parts.last()? {
+            Expr::Variable(name) => name,
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    match ending {
+        "" if line.ends_with(&name[..]) => Some((name.clone(), false)),
+        "}" => Some((name.clone(), true)),
+        PLACEHOLDER => Some((String::from(name.strip_suffix(PLACEHOLDER)?), false)),
+        "_}" => Some((String::from(name.strip_suffix(PLACEHOLDER)?), true)),
+        _ => None,
+    }
+}
+
+/// Find the word before the cursor. Returns `None` if the line can't be lexed even after
+/// closing an open quote and finishing a variable name.
+fn current_word(line: &str, variables: &Variables) -> Option<Word> {
+    // Try closing each kind of quote, and finishing a variable name which is empty or missing
+    // its `}`. A variable being typed is preferred, and otherwise the line as it is.
+    let closings = [("", Quote::None), ("\"", Quote::Double), ("'", Quote::Single)];
+    let endings = ["", "}", PLACEHOLDER, "_}"];
+    let attempts: Vec<(Vec<Token>, Quote, &str)> = closings.iter().flat_map(|&(closing, quote)| {
+        endings.iter().filter_map(move |&ending| {
+            let source = format!("{}{}{}", line, ending, closing);
+            let tokens = Lexer::new(&source).collect::<Result<Vec<Token>, _>>().ok()?;
+            Some((tokens, quote, ending))
+        })
+    }).collect();
+
+    let typed = attempts.iter().find_map(|&(ref tokens, quote, ending)| {
+        typed_variable(line, tokens, ending).map(|variable| (Some(variable), tokens, quote))
+    });
+    let (variable, tokens, quote) = match typed {
+        Some(typed) => typed,
+        None => {
+            let &(ref tokens, quote, _) = attempts.iter().find(|attempt| attempt.2.is_empty())?;
+            (None, tokens, quote)
+        },
+    };
+
+    // An empty token list is the start of a command, as is anything after an operator which
+    // separates commands. The word in progress is made of the tokens since the last whitespace
+    // or operator.
+    let mut expect_command = true;
+    let mut is_command = true;
+    let mut command = String::new();
+    let mut value = String::new();
+    let mut in_word = false;
+
+    for token in tokens.iter().cloned() {
+        match token {
+            Token::Text(text) | Token::Glob(text) => {
+                value.push_str(&text);
+                in_word = true;
+            },
+
+            Token::Variable(name) => {
+                let var_value = variables.get(&name).unwrap_or_default();
+                value.push_str(var_value.first().map_or("", |v| &v[..]));
+                in_word = true;
+            },
+
+            Token::Tilde(name) => {
+                match tilde::expand(&name, variables) {
+                    Ok(dir) => value.push_str(&dir),
+                    Err(_) => value.push_str(&format!("~{}", name)),
+                }
+                in_word = true;
+            },
+
+            // Each alternative in braces is completed on its own.
+            Token::BraceOpen | Token::Comma => {
+                value.clear();
+                in_word = true;
+            },
+
+            Token::BraceClose => value.push('}'),
+
+            Token::CommandSubstitution(_) | Token::LineSubstitution(_) => in_word = true,
+
+            Token::Quoted(parts) => {
+                for part in parts {
+                    match part {
+                        Expr::Text(text) => value.push_str(&text),
+                        Expr::Variable(name) => {
+                            value.push_str(&variables.get(&name).unwrap_or_default().join(" "));
+                        },
+                        _ => {},
+                    }
+                }
+                in_word = true;
+            },
+
+            Token::Whitespace => {
+                if in_word && expect_command {
+                    command = value.clone();
+                    expect_command = false;
+                }
+                is_command = expect_command;
+                value.clear();
+                in_word = false;
+            },
+
+            Token::Redirect(..) => {
+                is_command = false;
+                value.clear();
+                in_word = false;
+            },
+
+            Token::Newline | Token::Semicolon | Token::And | Token::Or | Token::Ampersand |
+            Token::Pipe | Token::CloseParen => {
+                expect_command = true;
+                is_command = true;
+                command.clear();
+                value.clear();
+                in_word = false;
+            },
+        }
+    }
+
+    // The quote closed to make the line lex only counts if it's part of the word.
+    let quote = if in_word || quote == Quote::None { quote } else { Quote::None };
+
+    Some(Word { value, is_command, command, quote, variable })
+}
+
+/// Quote text to be inserted at the end of a word, given the quoting open there.
+fn quote(text: &str, quote: Quote) -> String {
+    let mut quoted = String::with_capacity(text.len());
+    for c in text.chars() {
+        match quote {
+            Quote::None if !Lexer::is_unquoted_text(c) => {
+                quoted.push('\\');
+                quoted.push(c);
+            },
+            Quote::Double if c == '"' || c == '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            },
+            // Single-quoted text can't contain a `'`, so close the quotes around it.
+            Quote::Single if c == '\'' => quoted.push_str("'\\''"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted
+}
+
+fn closing_quote(quote: Quote) -> &'static str {
+    match quote {
+        Quote::None => "",
+        Quote::Single => "'",
+        Quote::Double => "\"",
+    }
+}
+
+fn longest_common_prefix(strings: &[String]) -> String {
+    let first = match strings.first() {
+        Some(first) => first,
+        None => return String::new(),
+    };
+
+    let mut len = first.len();
+    for s in &strings[1..] {
+        len = first.char_indices().zip(s.chars())
+            .take_while(|&((i, a), b)| i < len && a == b)
+            .map(|((i, a), _)| i + a.len_utf8())
+            .last()
+            .unwrap_or(0);
+    }
+    String::from(&first[..len])
+}
+
+/// The directories in `$PATH`, which may be a single colon-separated value or a list.
+fn path_dirs(variables: &Variables) -> Vec<String> {
+    variables.get("PATH").unwrap_or_default().iter()
+        .flat_map(|value| value.split(':'))
+        .filter(|dir| !dir.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+/// Executables in `$PATH` whose names start with `prefix`.
+fn path_commands(prefix: &str, variables: &Variables) -> Vec<String> {
+    let mut commands = vec![];
+    for dir in path_dirs(variables) {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+
+        for entry in entries.filter_map(Result::ok) {
+            if let Ok(name) = entry.file_name().into_string() {
+                if name.starts_with(prefix) && is_executable(&entry.path()) {
+                    commands.push(name);
+                }
+            }
+        }
+    }
+    commands
+}
+
+/// Paths starting with `prefix`, with a `/` after directory names. Hidden files are only
+/// included if the prefix's file name starts with a dot.
+fn paths(prefix: &str, executables_only: bool) -> Vec<String> {
+    let (dir, file_prefix) = match prefix.rfind('/') {
+        Some(i) => (&prefix[..i + 1], &prefix[i + 1..]),
+        None => ("", prefix),
+    };
+
+    let entries = match fs::read_dir(if dir.is_empty() { "." } else { dir }) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    let mut paths = vec![];
+    for entry in entries.filter_map(Result::ok) {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+
+        let hidden = name.starts_with('.') && !file_prefix.starts_with('.');
+        if hidden || !name.starts_with(file_prefix) {
+            continue;
+        }
+
+        let path = entry.path();
+        if path.is_dir() {
+            paths.push(format!("{}{}/", dir, name));
+        } else if !executables_only || is_executable(&path) {
+            paths.push(format!("{}{}", dir, name));
+        }
+    }
+    paths
+}
+
+/// Variable names starting with `prefix`, from both the shell and the environment.
+fn variable_names(prefix: &str, variables: &Variables) -> Vec<String> {
+    variables.all().into_iter().map(|(name, _)| String::from(name))
+        .chain(env::vars_os().filter_map(|(name, _)| name.into_string().ok()))
+        .filter(|name| name.starts_with(prefix) && variables.get(name).is_some())
+        .collect()
+}
+
+/// If the word at the end of `line` is an abbreviation typed as a command, return the length in
+/// characters of the word to replace and the text to replace it with.
+pub fn abbreviation(line: &str, abbreviations: &HashMap<String, String>,
+                    variables: &Variables) -> Option<(usize, String)> {
+    let word = current_word(line, variables)?;
+    // Only a word typed as is counts, not one which is quoted or comes from a variable.
+    if !word.is_command || word.quote != Quote::None || word.variable.is_some() ||
+       !line.ends_with(&word.value) {
+        return None;
+    }
+    let expansion = abbreviations.get(&word.value)?;
+    Some((word.value.chars().count(), expansion.clone()))
+}
+
+/// Complete the word which ends at the end of `line`, the text before the cursor. Builtins and
+/// `functions` are completed along with executables in `$PATH` for the first word of a command,
+/// with builtins listed along with their descriptions. A word starting with `-` completes to
+/// the command's flags if it's a builtin with flags. Other words complete to file paths, and
+/// names after `$` complete to variables.
+pub fn complete(line: &str, builtins: &Registry, functions: &[&str], variables: &Variables)
+                -> Completions {
+    let word = match current_word(line, variables) {
+        Some(word) => word,
+        None => return Completions { insert: String::new(), candidates: vec![] },
+    };
+
+    if let Some((ref prefix, braced)) = word.variable {
+        let mut names = variable_names(prefix, variables);
+        names.sort();
+        names.dedup();
+        let terminator = if braced { "}" } else { "" };
+        return finish(prefix, names, |name| name.clone(), Quote::None, terminator);
+    }
+
+    let terminator = format!("{} ", closing_quote(word.quote));
+
+    if word.value.starts_with('-') && !word.is_command {
+        let flags = builtins.flags(&word.command);
+        let candidates: Vec<String> = flags.iter()
+            .filter(|flag| flag.name.starts_with(&word.value[..]))
+            .map(|flag| String::from(flag.name))
+            .collect();
+        if !candidates.is_empty() {
+            return finish(&word.value, candidates, |candidate| {
+                let flag = flags.iter().find(|flag| flag.name == candidate).unwrap();
+                format!("{}  ({})", flag.name, flag.description)
+            }, word.quote, &terminator);
+        }
+    }
+
+    let mut candidates = if word.is_command && !word.value.contains('/') {
+        let mut candidates: Vec<String> = builtins.names().into_iter()
+            .chain(functions.iter().cloned())
+            .filter(|name| name.starts_with(&word.value[..]))
+            .map(String::from)
+            .collect();
+        candidates.extend(path_commands(&word.value, variables));
+        candidates
+    } else {
+        paths(&word.value, word.is_command)
+    };
+    candidates.sort();
+    candidates.dedup();
+
+    finish(&word.value, candidates, |candidate| {
+        if word.is_command {
+            if let Some(builtin) = builtins.get(candidate) {
+                return format!("{}  ({})", candidate, builtin.info().description);
+            }
+        }
+
+        // List files by name rather than by their whole path.
+        let name = candidate.trim_end_matches('/');
+        let name = &name[name.rfind('/').map_or(0, |i| i + 1)..];
+        if candidate.ends_with('/') { format!("{}/", name) } else { String::from(name) }
+    }, word.quote, &terminator)
+}
+
+/// Build the completions for `prefix` from the sorted candidates, which all start with it. With
+/// one candidate, the rest of it is inserted followed by `terminator`, unless it's a directory
+/// so completion can continue inside it. Otherwise the rest of the candidates' common prefix is
+/// inserted and the candidates are listed.
+fn finish<F>(prefix: &str, candidates: Vec<String>, display: F, quote_kind: Quote,
+             terminator: &str) -> Completions where F: Fn(&String) -> String {
+    if candidates.len() == 1 {
+        let candidate = &candidates[0];
+        let terminator = if candidate.ends_with('/') { "" } else { terminator };
+        let insert = format!("{}{}", quote(&candidate[prefix.len()..], quote_kind), terminator);
+        return Completions { insert, candidates: vec![] };
+    }
+
+    let common_prefix = longest_common_prefix(&candidates);
+    let insert = quote(common_prefix.get(prefix.len()..).unwrap_or(""), quote_kind);
+    Completions { insert, candidates: candidates.iter().map(display).collect() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::current_word;
+    use variables::Variables;
+
+    fn variable(line: &str) -> Option<(String, bool)> {
+        current_word(line, &Variables::new()).and_then(|word| word.variable)
+    }
+
+    #[test]
+    fn variables_being_typed() {
+        assert_eq!(variable("echo $HO"), Some((String::from("HO"), false)));
+        assert_eq!(variable("echo ${HO"), Some((String::from("HO"), true)));
+        assert_eq!(variable("echo \"$HO"), Some((String::from("HO"), false)));
+        assert_eq!(variable("echo \"a${HO"), Some((String::from("HO"), true)));
+        assert_eq!(variable("echo $"), Some((String::new(), false)));
+        assert_eq!(variable("echo ${"), Some((String::new(), true)));
+    }
+
+    #[test]
+    fn not_variables_being_typed() {
+        assert_eq!(variable("echo '$HO"), None);
+        assert_eq!(variable("echo \\$HO"), None);
+        assert_eq!(variable("echo ${HOME}"), None);
+        assert_eq!(variable("echo \"$HOME\""), None);
+        assert_eq!(variable("echo $HOME/"), None);
+        assert_eq!(variable("echo HO"), None);
+    }
+}
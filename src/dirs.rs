@@ -0,0 +1,169 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+
+use tilde;
+use variables::Variables;
+
+/// The most directories `prevd` can go back through.
+const MAX_HISTORY: usize = 25;
+
+/// The directory stack for `pushd` and `popd`, and the history of directories visited for
+/// `prevd` and `nextd`.
+#[derive(Clone, Debug, Default)]
+pub struct Dirs {
+    /// Directories saved by `pushd`, most recent last.
+    pub stack: Vec<PathBuf>,
+
+    /// Directories changed away from, most recent last.
+    back: Vec<PathBuf>,
+
+    /// Directories `prevd` went back from, for `nextd` to return to, most recent last.
+    forward: Vec<PathBuf>,
+}
+
+impl Dirs {
+    pub fn new() -> Dirs {
+        Dirs::default()
+    }
+
+    /// Record a change of directory away from `old`, which starts a new path through history
+    /// so there's nothing for `nextd` to go to.
+    pub fn record(&mut self, old: PathBuf) {
+        self.back.push(old);
+        if self.back.len() > MAX_HISTORY {
+            self.back.remove(0);
+        }
+        self.forward.clear();
+    }
+
+    /// The directory `count` steps back or forward through history, if it goes that far.
+    pub fn history(&self, forward: bool, count: usize) -> Option<&Path> {
+        let dirs = if forward { &self.forward } else { &self.back };
+        let index = dirs.len().checked_sub(count).filter(|_| count > 0)?;
+        Some(&dirs[index])
+    }
+
+    /// Record moving `count` steps back or forward through history from `current`, once the
+    /// directory from `history` has been changed to.
+    pub fn step(&mut self, forward: bool, count: usize, mut current: PathBuf) {
+        let (from, to) = if forward {
+            (&mut self.forward, &mut self.back)
+        } else {
+            (&mut self.back, &mut self.forward)
+        };
+        for _ in 0..count {
+            to.push(current);
+            current = from.pop().unwrap();
+        }
+    }
+
+    /// The directories in history, oldest first, with the index of the current directory,
+    /// which is `current`.
+    pub fn list_history(&self, current: PathBuf) -> (Vec<PathBuf>, usize) {
+        let mut dirs = self.back.clone();
+        dirs.push(current);
+        dirs.extend(self.forward.iter().rev().cloned());
+        (dirs, self.back.len())
+    }
+}
+
+/// Whether two paths are the same file, following symlinks.
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// The logical current directory, which is `$PWD` if it's an absolute path to the current
+/// directory, so it keeps any symlinks used to get there. Otherwise it's the physical current
+/// directory.
+pub fn logical_cwd(variables: &Variables) -> io::Result<PathBuf> {
+    if let Some(pwd) = variables.get("PWD") {
+        let pwd = PathBuf::from(pwd.join(" "));
+        if pwd.is_absolute() && same_file(&pwd, Path::new(".")) {
+            return Ok(pwd);
+        }
+    }
+    env::current_dir()
+}
+
+/// Set and export `$PWD` when the shell starts.
+pub fn init(variables: &mut Variables) {
+    if let Ok(pwd) = logical_cwd(variables) {
+        variables.set("PWD", vec![pwd.to_string_lossy().into_owned()]);
+        variables.export("PWD");
+    }
+}
+
+/// Remove `.` components and resolve `..` components by dropping the component before,
+/// without looking at the file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => {
+                normalized.pop();
+            },
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Find a relative directory in one of the directories in `$CDPATH`, which may be a single
+/// colon-separated value or a list. An empty entry is the current directory, so the path is
+/// returned as is. Paths starting with `.` or `..` aren't searched for.
+pub fn search_cdpath(target: &str, variables: &Variables) -> Option<PathBuf> {
+    let path = Path::new(target);
+    match path.components().next() {
+        Some(Component::Normal(_)) => {},
+        _ => return None,
+    }
+
+    variables.get("CDPATH").unwrap_or_default().iter()
+        .flat_map(|value| value.split(':'))
+        .map(|dir| if dir.is_empty() { path.to_path_buf() } else { Path::new(dir).join(path) })
+        .find(|dir| dir.is_dir())
+}
+
+/// Change the current directory and update `$PWD` and `$OLDPWD`, returning the old directory.
+///
+/// When `physical`, like `cd -P`, symlinks are resolved and `$PWD` is the real path. Otherwise,
+/// like `cd -L`, `$PWD` keeps symlinks and `..` goes back through the path as written, so after
+/// `cd /link/dir`, `cd ..` goes to `/link` rather than the real parent of `dir`.
+pub fn change_dir(target: &Path, physical: bool, variables: &mut Variables)
+                  -> io::Result<PathBuf> {
+    let old = logical_cwd(variables)?;
+    let new = if physical {
+        env::set_current_dir(target)?;
+        env::current_dir()?
+    } else {
+        let new = normalize(&old.join(target));
+        env::set_current_dir(&new)?;
+        new
+    };
+
+    variables.set("OLDPWD", vec![old.to_string_lossy().into_owned()]);
+    variables.export("OLDPWD");
+    variables.set("PWD", vec![new.to_string_lossy().into_owned()]);
+    variables.export("PWD");
+    Ok(old)
+}
+
+/// A directory to show the user, with the home directory abbreviated to `~`.
+pub fn display(dir: &Path, variables: &Variables) -> String {
+    if let Some(home) = tilde::home_dir(variables) {
+        if let Ok(rest) = dir.strip_prefix(&home) {
+            if rest.as_os_str().is_empty() {
+                return String::from("~");
+            }
+            return format!("~/{}", rest.display());
+        }
+    }
+    dir.display().to_string()
+}
@@ -0,0 +1,638 @@
+use libc;
+use std::io::{self, Write};
+use std::mem;
+
+use history::History;
+
+/// How long to wait for the rest of an escape sequence before treating `Esc` as a key by itself.
+const ESCAPE_TIMEOUT_MS: libc::c_int = 50;
+
+/// Puts the terminal into raw mode for as long as it's alive, so keys are read one at a time
+/// without being echoed.
+struct RawMode {
+    original: libc::termios,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        unsafe {
+            let mut original: libc::termios = mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut raw = original;
+            raw.c_iflag &= !(libc::ICRNL | libc::IXON);
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &raw) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(RawMode { original })
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &self.original);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Key {
+    Char(char),
+    Ctrl(char),
+    Alt(char),
+    Backspace,
+    Delete,
+    Enter,
+    Tab,
+    Escape,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Unknown,
+}
+
+/// Read a byte from stdin without buffering, so nothing typed ahead is held back from the
+/// commands the shell runs. Returns `None` at EOF.
+fn read_byte() -> io::Result<Option<u8>> {
+    let mut byte = 0u8;
+    loop {
+        let buf = &mut byte as *mut u8 as *mut libc::c_void;
+        let n = unsafe { libc::read(libc::STDIN_FILENO, buf, 1) };
+        match n {
+            1 => return Ok(Some(byte)),
+            0 => return Ok(None),
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            },
+        }
+    }
+}
+
+/// Whether more input arrives on stdin within the timeout.
+fn input_pending(timeout_ms: libc::c_int) -> bool {
+    let mut fd = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+    unsafe { libc::poll(&mut fd, 1, timeout_ms) > 0 }
+}
+
+fn read_key() -> io::Result<Option<Key>> {
+    let byte = match read_byte()? {
+        Some(byte) => byte,
+        None => return Ok(None),
+    };
+
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        0x7f | 0x08 => Key::Backspace,
+        0x1b => read_escape()?,
+        0x01..=0x1a => Key::Ctrl((b'a' + byte - 1) as char),
+        0x00..=0x1f => Key::Unknown,
+        _ => read_utf8(byte)?,
+    };
+
+    Ok(Some(key))
+}
+
+/// Read the rest of a UTF-8 encoded character given its first byte.
+fn read_utf8(first: u8) -> io::Result<Key> {
+    let len = match first {
+        0x00..=0x7f => 1,
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return Ok(Key::Unknown),
+    };
+
+    let mut bytes = vec![first];
+    while bytes.len() < len {
+        match read_byte()? {
+            Some(byte) => bytes.push(byte),
+            None => return Ok(Key::Unknown),
+        }
+    }
+
+    Ok(String::from_utf8(bytes).ok()
+        .and_then(|s| s.chars().next())
+        .map_or(Key::Unknown, Key::Char))
+}
+
+/// Read the rest of an escape sequence after `Esc`: either a terminal's encoding of a special
+/// key, or `Esc` followed by a character, which is how terminals send `Alt` combinations.
+fn read_escape() -> io::Result<Key> {
+    if !input_pending(ESCAPE_TIMEOUT_MS) {
+        return Ok(Key::Escape);
+    }
+
+    let byte = match read_byte()? {
+        Some(byte) => byte,
+        None => return Ok(Key::Escape),
+    };
+
+    match byte {
+        b'[' => {
+            // A control sequence: numeric parameters separated by `;`, then a final byte.
+            let mut params = String::new();
+            let final_byte = loop {
+                match read_byte()? {
+                    Some(b @ 0x40..=0x7e) => break b,
+                    Some(b) => params.push(b as char),
+                    None => return Ok(Key::Unknown),
+                }
+            };
+
+            // With a modifier like `Ctrl` (`1;5C`), arrows move by words.
+            let modified = params.contains(';');
+            Ok(match (final_byte, &params[..]) {
+                (b'A', _) => Key::Up,
+                (b'B', _) => Key::Down,
+                (b'C', _) if modified => Key::Alt('f'),
+                (b'D', _) if modified => Key::Alt('b'),
+                (b'C', _) => Key::Right,
+                (b'D', _) => Key::Left,
+                (b'H', _) | (b'~', "1") | (b'~', "7") => Key::Home,
+                (b'F', _) | (b'~', "4") | (b'~', "8") => Key::End,
+                (b'~', "3") => Key::Delete,
+                _ => Key::Unknown,
+            })
+        },
+
+        b'O' => {
+            Ok(match read_byte()? {
+                Some(b'A') => Key::Up,
+                Some(b'B') => Key::Down,
+                Some(b'C') => Key::Right,
+                Some(b'D') => Key::Left,
+                Some(b'H') => Key::Home,
+                Some(b'F') => Key::End,
+                _ => Key::Unknown,
+            })
+        },
+
+        0x7f | 0x08 => Ok(Key::Alt('\x7f')),
+
+        _ => match read_utf8(byte)? {
+            Key::Char(c) => Ok(Key::Alt(c)),
+            _ => Ok(Key::Unknown),
+        },
+    }
+}
+
+/// The width of the terminal in columns.
+fn terminal_width() -> usize {
+    unsafe {
+        let mut size: libc::winsize = mem::zeroed();
+        if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) == 0 && size.ws_col > 0 {
+            size.ws_col as usize
+        } else {
+            80
+        }
+    }
+}
+
+/// The row and column on the screen after writing `chars` from the start of a row, where rows
+/// are `width` columns wide.
+fn screen_position<I>(chars: I, width: usize) -> (usize, usize) where I: Iterator<Item = char> {
+    let (mut row, mut col) = (0, 0);
+    for c in chars {
+        if c == '\n' || col == width {
+            row += 1;
+            col = 0;
+        }
+        if c != '\n' {
+            col += 1;
+        }
+    }
+
+    if col == width {
+        (row + 1, 0)
+    } else {
+        (row, col)
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// The line being edited and where it is on the screen.
+struct Line<'a> {
+    prompt: &'a str,
+    buffer: Vec<char>,
+    cursor: usize,
+
+    /// How many rows below the start of the prompt the terminal cursor is, when the line has
+    /// wrapped.
+    cursor_row: usize,
+}
+
+impl<'a> Line<'a> {
+    fn new(prompt: &'a str) -> Line<'a> {
+        Line { prompt, buffer: vec![], cursor: 0, cursor_row: 0 }
+    }
+
+    fn text(&self) -> String {
+        self.buffer.iter().collect()
+    }
+
+    fn set_text(&mut self, text: &str) {
+        self.buffer = text.chars().collect();
+        self.cursor = self.buffer.len();
+    }
+
+    fn insert(&mut self, text: &str) {
+        for c in text.chars() {
+            self.buffer.insert(self.cursor, c);
+            self.cursor += 1;
+        }
+    }
+
+    /// Remove the characters between `start` and `end`, returning them.
+    fn remove(&mut self, start: usize, end: usize) -> String {
+        let removed = self.buffer.drain(start..end).collect();
+        if self.cursor > end {
+            self.cursor -= end - start;
+        } else if self.cursor > start {
+            self.cursor = start;
+        }
+        removed
+    }
+
+    fn word_start_before(&self, mut pos: usize) -> usize {
+        while pos > 0 && !is_word_char(self.buffer[pos - 1]) {
+            pos -= 1;
+        }
+        while pos > 0 && is_word_char(self.buffer[pos - 1]) {
+            pos -= 1;
+        }
+        pos
+    }
+
+    fn word_end_after(&self, mut pos: usize) -> usize {
+        while pos < self.buffer.len() && !is_word_char(self.buffer[pos]) {
+            pos += 1;
+        }
+        while pos < self.buffer.len() && is_word_char(self.buffer[pos]) {
+            pos += 1;
+        }
+        pos
+    }
+
+    /// The start of the whitespace-separated word before the cursor, for `Ctrl-W`.
+    fn big_word_start_before(&self, mut pos: usize) -> usize {
+        while pos > 0 && self.buffer[pos - 1].is_whitespace() {
+            pos -= 1;
+        }
+        while pos > 0 && !self.buffer[pos - 1].is_whitespace() {
+            pos -= 1;
+        }
+        pos
+    }
+
+    fn redraw(&mut self) -> io::Result<()> {
+        let text = self.text();
+        let prompt = self.prompt;
+        self.draw(prompt, &text, self.cursor)
+    }
+
+    /// Redraw the whole line, which may have wrapped onto several rows or contain newlines, and
+    /// put the terminal cursor at `cursor` characters into `text`.
+    fn draw(&mut self, prompt: &str, text: &str, cursor: usize) -> io::Result<()> {
+        let width = terminal_width();
+        let prompt_len = prompt.chars().count();
+        let (end_row, end_col) = screen_position(prompt.chars().chain(text.chars()), width);
+        let (target_row, target_col) =
+            screen_position(prompt.chars().chain(text.chars()).take(prompt_len + cursor), width);
+
+        let mut out = io::stdout();
+        if self.cursor_row > 0 {
+            write!(out, "\x1b[{}A", self.cursor_row)?;
+        }
+        write!(out, "\r\x1b[J{}{}", prompt, text.replace('\n', "\r\n"))?;
+
+        // A line exactly filling its last row leaves the cursor past the edge of the screen
+        // until more is written, so move it down explicitly.
+        if end_col == 0 && end_row > 0 && !text.ends_with('\n') {
+            write!(out, "\r\n")?;
+        }
+
+        if end_row > target_row {
+            write!(out, "\x1b[{}A", end_row - target_row)?;
+        }
+        write!(out, "\r")?;
+        if target_col > 0 {
+            write!(out, "\x1b[{}C", target_col)?;
+        }
+
+        self.cursor_row = target_row;
+        out.flush()
+    }
+}
+
+/// The result of completing the word before the cursor.
+pub struct Completions {
+    /// Text to insert at the cursor, already quoted as needed.
+    pub insert: String,
+
+    /// The possible completions to list when there is more than one.
+    pub candidates: Vec<String>,
+}
+
+/// Print completion candidates below the line in columns, as `ls` does.
+fn list_candidates(candidates: &[String]) -> io::Result<()> {
+    let width = terminal_width();
+    let column_width = candidates.iter().map(|c| c.chars().count()).max().unwrap_or(0) + 2;
+    let columns = (width / column_width).max(1);
+    let rows = candidates.len().div_ceil(columns);
+
+    let mut out = io::stdout();
+    writeln!(out)?;
+    for row in 0..rows {
+        for column in 0..columns {
+            if let Some(candidate) = candidates.get(column * rows + row) {
+                write!(out, "{:1$}", candidate, column_width)?;
+            }
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// An interactive line editor with emacs-style keybindings and history.
+pub struct Editor {
+    pub history: History,
+
+    /// The text most recently removed by a kill command like `Ctrl-K`, for `Ctrl-Y`.
+    kill_buffer: String,
+}
+
+impl Editor {
+    pub fn new(history: History) -> Editor {
+        Editor { history, kill_buffer: String::new() }
+    }
+
+    /// Read a line from the terminal, which must be stdin. Returns `None` at EOF or when `Ctrl-D`
+    /// is pressed on an empty line. Pressing `Tab` calls `complete` with the text before the
+    /// cursor, and `Ctrl-C` discards the line, returning an `Interrupted` error.
+    ///
+    /// Pressing space or `Enter` calls `abbreviate` with the text before the cursor, which can
+    /// return the number of characters before the cursor to replace and their replacement.
+    pub fn read_line<F, A>(&mut self, prompt: &str, complete: F, abbreviate: A)
+                           -> io::Result<Option<String>>
+            where F: Fn(&str) -> Completions, A: Fn(&str) -> Option<(usize, String)> {
+        let _raw_mode = RawMode::enable()?;
+        let mut line = Line::new(prompt);
+
+        // The position in the history being shown, where `entries().len()` means the line being
+        // edited, which is saved while browsing.
+        let mut history_index = self.history.entries().len();
+        let mut edited_line = String::new();
+
+        line.redraw()?;
+
+        let mut pending_key = None;
+        loop {
+            let key = match pending_key.take() {
+                Some(key) => key,
+                None => match read_key()? {
+                    Some(key) => key,
+                    None => return Ok(None),
+                },
+            };
+
+            if key == Key::Enter || key == Key::Char(' ') {
+                if key == Key::Enter {
+                    line.cursor = line.buffer.len();
+                }
+                let before_cursor: String = line.buffer[..line.cursor].iter().collect();
+                if let Some((len, expansion)) = abbreviate(&before_cursor) {
+                    let cursor = line.cursor;
+                    line.remove(cursor - len, cursor);
+                    line.insert(&expansion);
+                }
+            }
+
+            match key {
+                Key::Enter => {
+                    line.cursor = line.buffer.len();
+                    line.redraw()?;
+                    println!();
+                    return Ok(Some(line.text()));
+                },
+
+                Key::Ctrl('d') if line.buffer.is_empty() => {
+                    return Ok(None);
+                },
+
+                Key::Ctrl('c') => {
+                    line.cursor = line.buffer.len();
+                    line.redraw()?;
+                    println!("^C");
+                    return Err(io::Error::from(io::ErrorKind::Interrupted));
+                },
+
+                Key::Char(c) => line.insert(&c.to_string()),
+
+                Key::Ctrl('a') | Key::Home => line.cursor = 0,
+                Key::Ctrl('e') | Key::End => line.cursor = line.buffer.len(),
+
+                Key::Ctrl('b') | Key::Left => {
+                    line.cursor = line.cursor.saturating_sub(1);
+                },
+
+                Key::Ctrl('f') | Key::Right => {
+                    line.cursor = (line.cursor + 1).min(line.buffer.len());
+                },
+
+                Key::Alt('b') => line.cursor = line.word_start_before(line.cursor),
+                Key::Alt('f') => line.cursor = line.word_end_after(line.cursor),
+
+                Key::Backspace if line.cursor > 0 => {
+                    let cursor = line.cursor;
+                    line.remove(cursor - 1, cursor);
+                },
+
+                Key::Ctrl('d') | Key::Delete if line.cursor < line.buffer.len() => {
+                    let cursor = line.cursor;
+                    line.remove(cursor, cursor + 1);
+                },
+
+                Key::Ctrl('k') => {
+                    let (cursor, len) = (line.cursor, line.buffer.len());
+                    self.kill_buffer = line.remove(cursor, len);
+                },
+
+                Key::Ctrl('u') => {
+                    let cursor = line.cursor;
+                    self.kill_buffer = line.remove(0, cursor);
+                },
+
+                Key::Ctrl('w') => {
+                    let cursor = line.cursor;
+                    let start = line.big_word_start_before(cursor);
+                    self.kill_buffer = line.remove(start, cursor);
+                },
+
+                Key::Alt('\x7f') => {
+                    let cursor = line.cursor;
+                    let start = line.word_start_before(cursor);
+                    self.kill_buffer = line.remove(start, cursor);
+                },
+
+                Key::Alt('d') => {
+                    let cursor = line.cursor;
+                    let end = line.word_end_after(cursor);
+                    self.kill_buffer = line.remove(cursor, end);
+                },
+
+                Key::Ctrl('y') => line.insert(&self.kill_buffer),
+
+                // Swap the two characters before the cursor, or around it mid-line.
+                Key::Ctrl('t') if line.buffer.len() >= 2 && line.cursor > 0 => {
+                    if line.cursor < line.buffer.len() {
+                        line.cursor += 1;
+                    }
+                    let cursor = line.cursor;
+                    line.buffer.swap(cursor - 2, cursor - 1);
+                },
+
+                Key::Ctrl('l') => {
+                    print!("\x1b[H\x1b[2J");
+                    line.cursor_row = 0;
+                },
+
+                Key::Ctrl('p') | Key::Up if history_index > 0 => {
+                    if history_index == self.history.entries().len() {
+                        edited_line = line.text();
+                    }
+                    history_index -= 1;
+                    line.set_text(&self.history.entries()[history_index].command);
+                },
+
+                Key::Ctrl('n') | Key::Down if history_index < self.history.entries().len() => {
+                    history_index += 1;
+                    if history_index == self.history.entries().len() {
+                        line.set_text(&edited_line);
+                    } else {
+                        line.set_text(&self.history.entries()[history_index].command);
+                    }
+                },
+
+                Key::Ctrl('r') => {
+                    pending_key = self.reverse_search(&mut line)?;
+                },
+
+                Key::Tab => {
+                    let before_cursor: String = line.buffer[..line.cursor].iter().collect();
+                    let completions = complete(&before_cursor);
+                    if !completions.insert.is_empty() {
+                        line.insert(&completions.insert);
+                    } else if !completions.candidates.is_empty() {
+                        // Move below the whole line before listing, then redraw it underneath.
+                        let cursor = line.cursor;
+                        line.cursor = line.buffer.len();
+                        line.redraw()?;
+                        list_candidates(&completions.candidates)?;
+                        line.cursor = cursor;
+                        line.cursor_row = 0;
+                    }
+                },
+
+                _ => {},
+            }
+
+            line.redraw()?;
+        }
+    }
+
+    /// Incrementally search backwards through history for entries containing what's typed.
+    /// Pressing `Ctrl-R` again finds the next older match, and `Ctrl-G` or `Esc` cancel the
+    /// search. Any other key leaves the match in the line and is returned to be handled as
+    /// usual, so `Enter` runs the match straight away.
+    fn reverse_search(&self, line: &mut Line) -> io::Result<Option<Key>> {
+        let original = line.text();
+        let original_cursor = line.cursor;
+        let entries = self.history.entries();
+        let mut query = String::new();
+
+        // The index of the entry currently matched, or `entries.len()` before any match.
+        let mut match_index = entries.len();
+        let mut failed = false;
+
+        let find = |query: &str, before: usize| {
+            entries[..before].iter().rposition(|entry| entry.command.contains(query))
+        };
+
+        loop {
+            let (text, cursor) = if match_index < entries.len() {
+                let command = &entries[match_index].command;
+                let offset = command.find(&query[..]).unwrap_or(0);
+                (command.clone(), command[..offset].chars().count())
+            } else {
+                (original.clone(), original_cursor)
+            };
+
+            let prompt = format!("({}reverse-i-search)`{}': ",
+                                 if failed { "failed " } else { "" }, query);
+            line.draw(&prompt, &text, cursor)?;
+            line.set_text(&text);
+            line.cursor = cursor;
+
+            let key = match read_key()? {
+                Some(key) => key,
+                None => Key::Ctrl('g'),
+            };
+
+            match key {
+                Key::Char(c) => {
+                    query.push(c);
+                    let before = (match_index + 1).min(entries.len());
+                    match find(&query, before) {
+                        Some(index) => {
+                            match_index = index;
+                            failed = false;
+                        },
+                        None => failed = true,
+                    }
+                },
+
+                Key::Backspace => {
+                    query.pop();
+                    match_index = find(&query, entries.len()).unwrap_or(entries.len());
+                    failed = false;
+                },
+
+                Key::Ctrl('r') => {
+                    if let Some(index) = find(&query, match_index) {
+                        match_index = index;
+                        failed = false;
+                    } else {
+                        failed = true;
+                    }
+                },
+
+                Key::Ctrl('g') | Key::Escape => {
+                    line.set_text(&original);
+                    line.cursor = original_cursor;
+                    return Ok(None);
+                },
+
+                key => return Ok(Some(key)),
+            }
+        }
+    }
+}
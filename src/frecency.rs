@@ -0,0 +1,195 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use history;
+
+/// When the ranks of all the directories add up to more than this, they're scaled down so
+/// directories which aren't visited any more are eventually forgotten.
+const MAX_TOTAL_RANK: f64 = 9000.0;
+
+/// How much ranks are scaled down by when they add up to too much. Directories whose rank falls
+/// below 1 are dropped.
+const AGING: f64 = 0.99;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub path: String,
+
+    /// How often the directory has been visited, scaled down over time.
+    pub rank: f64,
+
+    /// When the directory was last visited, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+impl Entry {
+    /// The frecency of the directory: its rank, weighted by how recently it was visited.
+    pub fn score(&self, now: u64) -> f64 {
+        let age = now.saturating_sub(self.timestamp);
+        let weight = if age < 60 * 60 {
+            4.0
+        } else if age < 24 * 60 * 60 {
+            2.0
+        } else if age < 7 * 24 * 60 * 60 {
+            0.5
+        } else {
+            0.25
+        };
+        self.rank * weight
+    }
+}
+
+/// The directories changed to in interactive shells, for `z` to jump to by frecency, which
+/// combines how often and how recently each was visited.
+///
+/// The database file has one directory per line: the rank, a tab, the timestamp, a tab, and the
+/// path. It's read again before each change is saved, so shells running at the same time don't
+/// lose each other's visits.
+#[derive(Clone, Debug)]
+pub struct Database {
+    entries: Vec<Entry>,
+    path: Option<PathBuf>,
+}
+
+/// The default location of the database, `~/.local/share/shroom/dirs`.
+pub fn default_path() -> Option<PathBuf> {
+    Some(history::data_dir()?.join("dirs"))
+}
+
+/// Whether `terms` appear in `path` in order. The last term has to be in the last component of
+/// the path, unless it contains a `/`, so `z foo` prefers `foo` over the directories in it.
+fn matches(path: &str, terms: &[String]) -> bool {
+    let name_start = path.trim_end_matches('/').rfind('/').map_or(0, |i| i + 1);
+    let mut start = 0;
+    for (i, term) in terms.iter().enumerate() {
+        if i + 1 == terms.len() && !term.contains('/') {
+            start = start.max(name_start);
+        }
+        match path[start..].find(&term[..]) {
+            Some(found) => start += found + term.len(),
+            None => return false,
+        }
+    }
+    true
+}
+
+impl Database {
+    /// A database which is never saved.
+    pub fn in_memory() -> Database {
+        Database { entries: vec![], path: None }
+    }
+
+    /// Load the database at `path`, which is created when the first directory is visited if it
+    /// doesn't exist.
+    pub fn load(path: PathBuf) -> io::Result<Database> {
+        let mut database = Database { entries: vec![], path: Some(path) };
+        database.reload()?;
+        Ok(database)
+    }
+
+    /// Read the database file again. Malformed lines are skipped.
+    fn reload(&mut self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                self.entries.clear();
+                return Ok(());
+            },
+            Err(e) => return Err(e),
+        };
+
+        let mut entries = vec![];
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let mut fields = line.splitn(3, '\t');
+            if let (Some(rank), Some(timestamp), Some(path)) =
+                    (fields.next(), fields.next(), fields.next()) {
+                if let (Ok(rank), Ok(timestamp)) = (rank.parse(), timestamp.parse()) {
+                    entries.push(Entry { path: String::from(path), rank, timestamp });
+                }
+            }
+        }
+        self.entries = entries;
+        Ok(())
+    }
+
+    /// Record a visit to the absolute directory `dir` and save the database. Directories whose
+    /// paths can't be stored on one line are ignored.
+    pub fn visit(&mut self, dir: &Path) -> io::Result<()> {
+        let dir = match dir.to_str() {
+            Some(dir) if !dir.contains('\n') => dir,
+            _ => return Ok(()),
+        };
+
+        self.reload()?;
+        let timestamp = history::now();
+        match self.entries.iter_mut().find(|entry| entry.path == dir) {
+            Some(entry) => {
+                entry.rank += 1.0;
+                entry.timestamp = timestamp;
+            },
+            None => self.entries.push(Entry { path: String::from(dir), rank: 1.0, timestamp }),
+        }
+
+        let total: f64 = self.entries.iter().map(|entry| entry.rank).sum();
+        if total > MAX_TOTAL_RANK {
+            for entry in &mut self.entries {
+                entry.rank *= AGING;
+            }
+            self.entries.retain(|entry| entry.rank >= 1.0);
+        }
+
+        self.save()
+    }
+
+    /// The directories which still exist and match `terms`, with their scores, best first.
+    /// Terms are matched case-sensitively if any directory matches that way, and otherwise
+    /// ignoring case.
+    pub fn search(&self, terms: &[String]) -> Vec<(&Entry, f64)> {
+        let now = history::now();
+        let existing: Vec<&Entry> = self.entries.iter()
+            .filter(|entry| Path::new(&entry.path).is_dir())
+            .collect();
+
+        let mut found: Vec<&Entry> = existing.iter().cloned()
+            .filter(|entry| matches(&entry.path, terms))
+            .collect();
+        if found.is_empty() {
+            let terms: Vec<String> = terms.iter().map(|term| term.to_lowercase()).collect();
+            found = existing.into_iter()
+                .filter(|entry| matches(&entry.path.to_lowercase(), &terms))
+                .collect();
+        }
+
+        let mut scored: Vec<(&Entry, f64)> =
+            found.into_iter().map(|entry| (entry, entry.score(now))).collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored
+    }
+
+    /// Rewrite the database file from the entries in memory.
+    fn save(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        let mut contents = String::new();
+        for entry in &self.entries {
+            contents.push_str(&format!("{}\t{}\t{}\n", entry.rank, entry.timestamp, entry.path));
+        }
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Write to a temporary file first so a crash can't lose the whole database.
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, contents)?;
+        fs::rename(temp_path, path)
+    }
+}
@@ -0,0 +1,355 @@
+use std::fs;
+use std::path::Path;
+
+/// One element of a path component pattern.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Element {
+    Char(char),
+
+    /// `?`, matching any one character.
+    AnyChar,
+
+    /// `*`, matching any run of characters.
+    AnyRun,
+
+    /// `[...]`, matching one character in (or with `!` or `^`, not in) the given ranges.
+    Class { negated: bool, ranges: Vec<(char, char)> },
+}
+
+/// One `/`-separated component of a pattern.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Component {
+    /// A component without wildcards, which is used as is.
+    Literal(String),
+
+    Pattern(Vec<Element>),
+
+    /// `**`, matching any number of directories.
+    Recursive,
+}
+
+/// Escape the wildcard characters in `text`, so it matches only itself as part of a pattern.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Parse the class after a `[`, returning `None` if there's no closing `]`.
+fn parse_class<I>(chars: &mut I) -> Option<Element> where I: Iterator<Item = char> + Clone {
+    let mut lookahead = chars.clone();
+    let mut negated = false;
+    let mut ranges = vec![];
+
+    let mut next = lookahead.next()?;
+    if next == '!' || next == '^' {
+        negated = true;
+        next = lookahead.next()?;
+    }
+
+    // A `]` straight after the `[` is part of the class rather than closing it.
+    let mut first = true;
+    while next != ']' || first {
+        first = false;
+        let start = if next == '\\' { lookahead.next()? } else { next };
+        next = lookahead.next()?;
+
+        if next == '-' {
+            let mut after = lookahead.clone();
+            match after.next()? {
+                ']' => ranges.push((start, start)),
+                end => {
+                    let end = if end == '\\' { after.next()? } else { end };
+                    ranges.push((start, end));
+                    lookahead = after;
+                    next = lookahead.next()?;
+                },
+            }
+        } else {
+            ranges.push((start, start));
+        }
+    }
+
+    *chars = lookahead;
+    Some(Element::Class { negated, ranges })
+}
+
+fn parse_component(text: &str) -> Component {
+    if text == "**" {
+        return Component::Recursive;
+    }
+
+    let mut elements = vec![];
+    let mut literal = true;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let element = match c {
+            '\\' => Element::Char(chars.next().unwrap_or('\\')),
+            '?' => Element::AnyChar,
+            '*' => Element::AnyRun,
+            '[' => parse_class(&mut chars).unwrap_or(Element::Char('[')),
+            c => Element::Char(c),
+        };
+        if !matches!(element, Element::Char(_)) {
+            literal = false;
+        }
+        elements.push(element);
+    }
+
+    if literal {
+        Component::Literal(elements.into_iter().map(|element| match element {
+            Element::Char(c) => c,
+            _ => unreachable!(),
+        }).collect())
+    } else {
+        Component::Pattern(elements)
+    }
+}
+
+fn matches(pattern: &[Element], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((&Element::AnyRun, rest)) => (0..=name.len()).any(|i| matches(rest, &name[i..])),
+        Some((element, rest)) => {
+            let c = match name.first() {
+                Some(&c) => c,
+                None => return false,
+            };
+            let matched = match *element {
+                Element::Char(expected) => c == expected,
+                Element::AnyChar => true,
+                Element::Class { negated, ref ranges } => {
+                    ranges.iter().any(|&(start, end)| start <= c && c <= end) != negated
+                },
+                Element::AnyRun => unreachable!(),
+            };
+            matched && matches(rest, &name[1..])
+        },
+    }
+}
+
+/// The entries of the directory `path`, which is empty for the current directory, sorted by
+/// name.
+fn read_dir(path: &str) -> Vec<(String, fs::DirEntry)> {
+    let entries = match fs::read_dir(if path.is_empty() { "." } else { path }) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    let mut entries: Vec<_> = entries.filter_map(Result::ok)
+        .filter_map(|entry| entry.file_name().into_string().ok().map(|name| (name, entry)))
+        .collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries
+}
+
+/// Add the paths matching `components` under `path`, which is empty or ends with a `/`.
+fn expand_in(path: &str, components: &[Component], results: &mut Vec<String>) {
+    let (component, rest) = match components.split_first() {
+        Some(split) => split,
+        None => {
+            results.push(String::from(path));
+            return;
+        },
+    };
+
+    match *component {
+        // An empty component comes from a trailing or doubled `/`.
+        Component::Literal(ref name) if name.is_empty() => expand_in(path, rest, results),
+
+        Component::Literal(ref name) => {
+            let full_path = format!("{}{}", path, name);
+            if rest.is_empty() {
+                if fs::symlink_metadata(&full_path).is_ok() {
+                    results.push(full_path);
+                }
+            } else if Path::new(&full_path).is_dir() {
+                expand_in(&format!("{}/", full_path), rest, results);
+            }
+        },
+
+        Component::Pattern(ref pattern) => {
+            // Hidden files only match a pattern which starts with a literal dot.
+            let show_hidden = pattern.first() == Some(&Element::Char('.'));
+            for (name, entry) in read_dir(path) {
+                if name.starts_with('.') && !show_hidden {
+                    continue;
+                }
+                let chars: Vec<char> = name.chars().collect();
+                if !matches(pattern, &chars) {
+                    continue;
+                }
+
+                let full_path = format!("{}{}", path, name);
+                if rest.is_empty() {
+                    results.push(full_path);
+                } else if entry.path().is_dir() {
+                    expand_in(&format!("{}/", full_path), rest, results);
+                }
+            }
+        },
+
+        Component::Recursive => {
+            // A trailing `**` matches every file under the directory.
+            let any = [Component::Pattern(vec![Element::AnyRun])];
+            expand_in(path, if rest.is_empty() { &any } else { rest }, results);
+
+            // Symlinks aren't followed, so a link to a parent directory can't cause a loop.
+            for (name, entry) in read_dir(path) {
+                let is_dir = entry.file_type().map(|file_type| file_type.is_dir()).unwrap_or(false);
+                if is_dir && !name.starts_with('.') {
+                    expand_in(&format!("{}{}/", path, name), components, results);
+                }
+            }
+        },
+    }
+}
+
+/// The sorted paths matching a pattern, which can use `*`, `?` and `[...]` within a path
+/// component and `**` at the start of a component to match any number of directories.
+/// Wildcards can be escaped with a backslash.
+pub fn expand(pattern: &str) -> Vec<String> {
+    let (path, pattern) = match pattern.strip_prefix('/') {
+        Some(rest) => ("/", rest),
+        None => ("", pattern),
+    };
+
+    // A component starting with `**`, like `**.rs`, is the same as `**/*.rs`.
+    let components: Vec<Component> = pattern.split('/').flat_map(|text| {
+        match text.strip_prefix("**") {
+            Some(rest) if !rest.is_empty() => {
+                vec![Component::Recursive, parse_component(&format!("*{}", rest))]
+            },
+            _ => vec![parse_component(text)],
+        }
+    }).collect();
+    let mut results = vec![];
+    expand_in(path, &components, &mut results);
+    results.sort();
+    results.dedup();
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape, expand, matches, parse_component, Component, Element};
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Whether `pattern`, a single path component, matches `name`.
+    fn glob_matches(pattern: &str, name: &str) -> bool {
+        let chars: Vec<char> = name.chars().collect();
+        match parse_component(pattern) {
+            Component::Literal(literal) => literal == name,
+            Component::Pattern(elements) => matches(&elements, &chars),
+            Component::Recursive => panic!("`**` isn't matched against names"),
+        }
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(glob_matches("*.rs", "main.rs"));
+        assert!(glob_matches("*.rs", ".rs"));
+        assert!(!glob_matches("*.rs", "main.rc"));
+        assert!(glob_matches("a*b*c", "abbbc"));
+        assert!(!glob_matches("a*b*c", "acb"));
+        assert!(glob_matches("?.txt", "a.txt"));
+        assert!(!glob_matches("?.txt", ".txt"));
+        assert!(!glob_matches("?.txt", "ab.txt"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(glob_matches("[abc]", "b"));
+        assert!(!glob_matches("[abc]", "d"));
+        assert!(glob_matches("[a-c]x", "bx"));
+        assert!(!glob_matches("[a-c]x", "dx"));
+        assert!(glob_matches("[!a-c]", "d"));
+        assert!(glob_matches("[^a-c]", "d"));
+        assert!(!glob_matches("[!a-c]", "a"));
+    }
+
+    #[test]
+    fn class_edge_cases() {
+        // A `]` straight after the `[` or `[!` is part of the class.
+        assert!(glob_matches("[]a]", "]"));
+        assert!(glob_matches("[]a]", "a"));
+        assert!(glob_matches("[!]]", "a"));
+        assert!(!glob_matches("[!]]", "]"));
+
+        // A `-` at the end is literal.
+        assert!(glob_matches("[a-]", "-"));
+
+        // Escapes inside a class.
+        assert!(glob_matches("[\\]]", "]"));
+        assert!(glob_matches("[\\!a]", "!"));
+
+        // Without a closing `]`, the `[` is literal.
+        assert_eq!(parse_component("[ab"), Component::Literal(String::from("[ab")));
+        assert!(glob_matches("[ab*", "[abc"));
+    }
+
+    #[test]
+    fn parsed_classes() {
+        let component = parse_component("[!a-cx]");
+        let expected = Element::Class { negated: true, ranges: vec![('a', 'c'), ('x', 'x')] };
+        assert_eq!(component, Component::Pattern(vec![expected]));
+    }
+
+    #[test]
+    fn escaped_wildcards() {
+        assert!(glob_matches("\\*", "*"));
+        assert!(!glob_matches("\\*a", "ba"));
+        assert_eq!(parse_component("a\\?"), Component::Literal(String::from("a?")));
+    }
+
+    #[test]
+    fn escape_makes_text_literal() {
+        assert_eq!(escape("a*b?[c]\\d"), "a\\*b\\?\\[c]\\\\d");
+        for text in &["*", "a?b", "[x]", "back\\slash", "plain"] {
+            assert!(glob_matches(&escape(text), text));
+        }
+        assert!(!glob_matches(&escape("*"), "abc"));
+    }
+
+    /// A fresh directory for a test to make files in.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("shroom-glob-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn recursive_expansion() {
+        let dir = temp_dir("recursive");
+        for path in &["a.rs", "b.txt", "src/c.rs", "src/deep/d.rs", ".hidden/e.rs"] {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        let root = dir.to_str().unwrap();
+
+        let found = |pattern: &str| -> Vec<String> {
+            expand(&format!("{}/{}", root, pattern)).into_iter()
+                .map(|path| String::from(&path[root.len() + 1..]))
+                .collect()
+        };
+
+        assert_eq!(found("**/*.rs"), ["a.rs", "src/c.rs", "src/deep/d.rs"]);
+        assert_eq!(found("**.rs"), ["a.rs", "src/c.rs", "src/deep/d.rs"]);
+        assert_eq!(found("src/**"), ["src/c.rs", "src/deep", "src/deep/d.rs"]);
+        assert_eq!(found("*"), ["a.rs", "b.txt", "src"]);
+        assert_eq!(found(".*/*.rs"), [".hidden/e.rs"]);
+        assert_eq!(found("*/*/*.rs"), ["src/deep/d.rs"]);
+        assert!(found("*.none").is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The most entries kept in the history file.
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Entry {
+    /// When the command was entered, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub command: String,
+}
+
+/// The commands entered at the prompt, oldest first, with no duplicates. Each command keeps only
+/// its most recent position.
+///
+/// The history file has one entry per line: the timestamp, a tab, and the command with
+/// backslashes and newlines escaped.
+#[derive(Clone, Debug)]
+pub struct History {
+    entries: Vec<Entry>,
+    path: Option<PathBuf>,
+}
+
+/// The directory the shell keeps its data in, `~/.local/share/shroom`, or under
+/// `$XDG_DATA_HOME` if it is set.
+pub fn data_dir() -> Option<PathBuf> {
+    let data_dir = match env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => env::home_dir()?.join(".local/share"),
+    };
+    Some(data_dir.join("shroom"))
+}
+
+/// The default location of the history file, `~/.local/share/shroom/history`.
+pub fn default_path() -> Option<PathBuf> {
+    Some(data_dir()?.join("history"))
+}
+
+fn escape(command: &str) -> String {
+    command.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut command = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => command.push('\n'),
+                Some(c) => command.push(c),
+                None => command.push('\\'),
+            },
+            c => command.push(c),
+        }
+    }
+    command
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Append an entry to the history file at `path`, creating it if needed.
+fn append(path: &Path, entry: &Entry) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    writeln!(file, "{}\t{}", entry.timestamp, escape(&entry.command))
+}
+
+impl History {
+    /// A history which is never saved.
+    pub fn in_memory() -> History {
+        History { entries: vec![], path: None }
+    }
+
+    /// Load the history file at `path`, which is created when the first entry is added if it
+    /// doesn't exist. Malformed lines are skipped.
+    pub fn load(path: PathBuf) -> io::Result<History> {
+        let mut history = History { entries: vec![], path: Some(path) };
+        let file = match File::open(history.path.as_ref().unwrap()) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(history),
+            Err(e) => return Err(e),
+        };
+
+        let mut line_count = 0;
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            line_count += 1;
+            if let Some((timestamp, command)) = line.split_once('\t') {
+                if let Ok(timestamp) = timestamp.parse() {
+                    history.entries.push(Entry { timestamp, command: unescape(command) });
+                }
+            }
+        }
+
+        // Entries are appended to the file as they're added, so it accumulates duplicates.
+        // Drop all but the latest copy of each command and rewrite the file if anything changed.
+        let mut seen = HashSet::new();
+        let mut entries: Vec<Entry> = history.entries.drain(..).rev()
+            .filter(|entry| seen.insert(entry.command.clone()))
+            .take(MAX_ENTRIES)
+            .collect();
+        entries.reverse();
+        history.entries = entries;
+
+        if history.entries.len() != line_count {
+            history.save()?;
+        }
+
+        Ok(history)
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Add a command as the newest entry, removing any older copy of it, and append it to the
+    /// history file. The entry is kept in memory even if it can't be saved, and after an error
+    /// the history stops being saved, so the error is only reported once.
+    pub fn add(&mut self, command: &str) -> io::Result<()> {
+        if command.trim().is_empty() {
+            return Ok(());
+        }
+
+        self.entries.retain(|entry| entry.command != command);
+        self.entries.push(Entry { timestamp: now(), command: String::from(command) });
+
+        let result = match self.path {
+            Some(ref path) => append(path, self.entries.last().unwrap()),
+            None => Ok(()),
+        };
+        if result.is_err() {
+            self.path = None;
+        }
+        result
+    }
+
+    /// Rewrite the history file from the entries in memory.
+    fn save(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        let mut contents = String::new();
+        for entry in &self.entries {
+            contents.push_str(&format!("{}\t{}\n", entry.timestamp, escape(&entry.command)));
+        }
+
+        // Write to a temporary file first so a crash can't lose the whole history.
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, contents)?;
+        fs::rename(temp_path, path)
+    }
+}
@@ -0,0 +1,410 @@
+use libc;
+use std::io;
+use std::mem;
+use std::os::fd::RawFd;
+
+use signals;
+
+pub type Pid = libc::pid_t;
+
+/// How a process ended.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Termination {
+    Exited(i32),
+    Signaled { signal: i32, core_dumped: bool },
+}
+
+impl Termination {
+    /// The exit code a shell reports for this, which is `128 + signal` for signal deaths.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Termination::Exited(code) => code,
+            Termination::Signaled { signal, .. } => 128 + signal,
+        }
+    }
+
+    fn describe(self) -> String {
+        match self {
+            Termination::Exited(0) => String::from("Done"),
+            Termination::Exited(code) => format!("Exit {}", code),
+            Termination::Signaled { signal, core_dumped } => {
+                let core_dumped = if core_dumped { " (core dumped)" } else { "" };
+                format!("{}{}", signals::name(signal), core_dumped)
+            },
+        }
+    }
+}
+
+/// One command of a job: either an external process or a builtin which already finished.
+#[derive(Clone, Debug)]
+struct Process {
+    pid: Option<Pid>,
+    termination: Option<Termination>,
+    stopped: bool,
+}
+
+impl Process {
+    fn is_pending(&self) -> bool {
+        self.termination.is_none() && !self.stopped
+    }
+
+    /// Record a status from `waitpid`.
+    fn update(&mut self, status: libc::c_int) {
+        if libc::WIFSTOPPED(status) {
+            self.stopped = true;
+        } else if libc::WIFCONTINUED(status) {
+            self.stopped = false;
+        } else if libc::WIFSIGNALED(status) {
+            self.termination = Some(Termination::Signaled {
+                signal: libc::WTERMSIG(status),
+                core_dumped: libc::WCOREDUMP(status),
+            });
+        } else {
+            self.termination = Some(Termination::Exited(libc::WEXITSTATUS(status)));
+        }
+    }
+
+    /// Block until the process exits or stops.
+    fn wait(&mut self) {
+        let pid = match self.pid {
+            Some(pid) if self.is_pending() => pid,
+            _ => return,
+        };
+
+        let mut status = 0;
+        if unsafe { libc::waitpid(pid, &mut status, libc::WUNTRACED) } == pid {
+            self.update(status);
+        } else {
+            // The process was already reaped somehow, so its status is lost.
+            self.termination = Some(Termination::Exited(127));
+        }
+    }
+
+    /// Check for a change in state without blocking.
+    fn poll(&mut self) {
+        let pid = match self.pid {
+            Some(pid) if self.termination.is_none() => pid,
+            _ => return,
+        };
+
+        let mut status = 0;
+        let flags = libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED;
+        if unsafe { libc::waitpid(pid, &mut status, flags) } == pid {
+            self.update(status);
+        }
+    }
+}
+
+/// A pipeline which is running, stopped or finished, with its external processes in their own
+/// process group when job control is on.
+#[derive(Clone, Debug)]
+pub struct Job {
+    /// The number used to refer to the job, like `%1`, or 0 if it isn't in the job table.
+    pub id: usize,
+
+    /// The process group, or 0 if the job has no external processes.
+    pub pgid: Pid,
+
+    /// The command line the job was started from.
+    pub command: String,
+
+    processes: Vec<Process>,
+}
+
+impl Job {
+    pub fn new(command: String) -> Job {
+        Job { id: 0, pgid: 0, command, processes: vec![] }
+    }
+
+    pub fn add_process(&mut self, pid: Pid) {
+        if self.pgid == 0 {
+            self.pgid = pid;
+        }
+        self.processes.push(Process { pid: Some(pid), termination: None, stopped: false });
+    }
+
+    /// Add a command which ran in the shell itself, like a builtin, and has already finished.
+    pub fn add_finished(&mut self, exit_code: i32) {
+        self.processes.push(Process {
+            pid: None,
+            termination: Some(Termination::Exited(exit_code)),
+            stopped: false,
+        });
+    }
+
+    /// Whether the last command ran in the shell itself, like a builtin.
+    pub fn ends_in_shell(&self) -> bool {
+        self.processes.last().is_some_and(|process| process.pid.is_none())
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.processes.iter().all(|process| process.termination.is_some())
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        !self.is_done() && self.processes.iter().all(|p| p.termination.is_some() || p.stopped)
+    }
+
+    /// How the job's last command ended, once the job is done.
+    pub fn termination(&self) -> Termination {
+        self.processes.last()
+            .and_then(|process| process.termination)
+            .unwrap_or(Termination::Exited(0))
+    }
+
+    /// The exit codes of every command, for `$pipestatus`.
+    pub fn exit_codes(&self) -> Vec<i32> {
+        self.processes.iter()
+            .map(|process| process.termination.map_or(0, Termination::exit_code))
+            .collect()
+    }
+
+    /// The exit code of the job, which is `128 + SIGTSTP` if it stopped.
+    pub fn exit_code(&self) -> i32 {
+        if self.is_stopped() {
+            128 + libc::SIGTSTP
+        } else {
+            self.termination().exit_code()
+        }
+    }
+
+    /// Block until every process has exited or stopped.
+    fn wait(&mut self) {
+        for process in &mut self.processes {
+            process.wait();
+        }
+    }
+
+    fn poll(&mut self) {
+        for process in &mut self.processes {
+            process.poll();
+        }
+    }
+
+    /// Send `SIGCONT` to every process, resuming the job if it was stopped.
+    fn resume(&mut self) -> io::Result<()> {
+        if unsafe { libc::kill(-self.pgid, libc::SIGCONT) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for process in &mut self.processes {
+            process.stopped = false;
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> String {
+        if self.is_done() {
+            self.termination().describe()
+        } else if self.is_stopped() {
+            String::from("Stopped")
+        } else {
+            String::from("Running")
+        }
+    }
+}
+
+/// The terminal the shell is controlling, when job control is on.
+struct Terminal {
+    /// A copy of the shell's stdin which is closed on exec, so it stays available in child
+    /// processes until they exec even when their stdin is redirected.
+    fd: RawFd,
+    shell_pgid: Pid,
+
+    /// The terminal modes to restore when the shell takes the terminal back from a job.
+    modes: libc::termios,
+}
+
+/// The background and stopped jobs, plus the state needed to move jobs between the foreground
+/// and background.
+pub struct Jobs {
+    jobs: Vec<Job>,
+    terminal: Option<Terminal>,
+}
+
+impl Jobs {
+    pub fn new() -> Jobs {
+        Jobs { jobs: vec![], terminal: None }
+    }
+
+    /// Turn on job control for an interactive shell: put the shell in its own process group in
+    /// the foreground of the terminal on stdin, and start running each job in its own process
+    /// group.
+    pub fn enable_job_control(&mut self) -> io::Result<()> {
+        unsafe {
+            let fd = libc::fcntl(libc::STDIN_FILENO, libc::F_DUPFD_CLOEXEC, 10);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // If the shell was started in the background, wait until it's brought to the
+            // foreground before touching the terminal.
+            loop {
+                let pgid = libc::getpgrp();
+                if libc::tcgetpgrp(fd) == pgid {
+                    break;
+                }
+                libc::kill(-pgid, libc::SIGTTIN);
+            }
+
+            signals::init(true);
+
+            let shell_pgid = libc::getpid();
+            if libc::getpgrp() != shell_pgid && libc::setpgid(0, shell_pgid) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::tcsetpgrp(fd, shell_pgid) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut modes: libc::termios = mem::zeroed();
+            if libc::tcgetattr(fd, &mut modes) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            self.terminal = Some(Terminal { fd, shell_pgid, modes });
+        }
+        Ok(())
+    }
+
+    /// Turn job control off in a forked copy of the shell, leaving the terminal to the shell
+    /// which forked it.
+    pub fn disable_job_control(&mut self) {
+        self.terminal = None;
+    }
+
+    pub fn job_control(&self) -> bool {
+        self.terminal.is_some()
+    }
+
+    /// The terminal file descriptor for a child process to take the terminal with, if job
+    /// control is on.
+    pub fn terminal_fd(&self) -> Option<RawFd> {
+        self.terminal.as_ref().map(|terminal| terminal.fd)
+    }
+
+    /// Add a job to the table with the lowest unused id, making it the current job. Returns the
+    /// id.
+    pub fn add(&mut self, mut job: Job) -> usize {
+        let mut id = 1;
+        while self.jobs.iter().any(|job| job.id == id) {
+            id += 1;
+        }
+        job.id = id;
+        self.jobs.push(job);
+        id
+    }
+
+    /// Find a job from a job spec like `%2`, `2`, or `%` / `%+` for the current job, which is
+    /// the most recently started or stopped one.
+    pub fn find(&self, spec: Option<&str>) -> Option<usize> {
+        let id = match spec {
+            None | Some("%") | Some("%+") => return self.jobs.len().checked_sub(1),
+            Some(spec) => spec.trim_start_matches('%').parse::<usize>().ok()?,
+        };
+        self.jobs.iter().position(|job| job.id == id)
+    }
+
+    /// Remove a job from the table without affecting its processes.
+    pub fn remove(&mut self, index: usize) -> Job {
+        self.jobs.remove(index)
+    }
+
+    /// Describe each job, like `[1]+ Running    sleep 10 &`.
+    pub fn list(&self) -> Vec<String> {
+        self.jobs.iter().enumerate().map(|(i, job)| {
+            self.describe(job, i + 1 == self.jobs.len())
+        }).collect()
+    }
+
+    fn describe(&self, job: &Job, current: bool) -> String {
+        let running = !job.is_done() && !job.is_stopped();
+        format!("[{}]{} {:<10} {}{}", job.id, if current { "+" } else { " " }, job.state(),
+                job.command, if running { " &" } else { "" })
+    }
+
+    /// Run a job in the foreground until it finishes or stops, giving it the terminal while it
+    /// runs. A stopped job is put in the job table. If `resume` is set, the job is sent
+    /// `SIGCONT` first, as for `fg`.
+    pub fn run_foreground(&mut self, mut job: Job, resume: bool) -> Job {
+        let has_processes = job.pgid != 0;
+
+        if let Some(ref terminal) = self.terminal {
+            if has_processes {
+                unsafe { libc::tcsetpgrp(terminal.fd, job.pgid) };
+            }
+        }
+
+        if resume && has_processes {
+            if let Err(e) = job.resume() {
+                eprintln!("shroom: fg: {}", e);
+            }
+        }
+
+        job.wait();
+
+        // Take the terminal back even if the job has no processes, since a child which failed
+        // to exec may still have taken it.
+        if let Some(ref terminal) = self.terminal {
+            unsafe {
+                libc::tcsetpgrp(terminal.fd, terminal.shell_pgid);
+                if has_processes {
+                    libc::tcsetattr(terminal.fd, libc::TCSADRAIN, &terminal.modes);
+                }
+            }
+        }
+
+        if job.is_stopped() {
+            if job.id == 0 {
+                self.add(job.clone());
+            } else {
+                self.jobs.push(job.clone());
+            }
+            let stopped = self.jobs.last().unwrap();
+            eprintln!("{}", self.describe(stopped, true));
+        }
+
+        job
+    }
+
+    /// Resume a stopped job in the background.
+    pub fn resume_background(&mut self, index: usize) -> io::Result<()> {
+        self.jobs[index].resume()?;
+        let job = self.jobs.remove(index);
+        self.jobs.push(job);
+        Ok(())
+    }
+
+    /// Wait for a background job to finish or stop, without giving it the terminal, and
+    /// remove it from the table if it finished.
+    pub fn wait(&mut self, index: usize) -> Job {
+        self.jobs[index].wait();
+        if self.jobs[index].is_done() {
+            self.jobs.remove(index)
+        } else {
+            self.jobs[index].clone()
+        }
+    }
+
+    /// Check for jobs which have changed state, removing finished jobs from the table. Returns
+    /// a description of each finished or newly stopped job, to be announced before the next
+    /// prompt.
+    pub fn reap(&mut self) -> Vec<String> {
+        if !signals::take_child_changed() {
+            return vec![];
+        }
+
+        let mut messages = vec![];
+        let len = self.jobs.len();
+        for i in 0..len {
+            let was_stopped = self.jobs[i].is_stopped();
+            self.jobs[i].poll();
+            let job = &self.jobs[i];
+            if job.is_done() || (job.is_stopped() && !was_stopped) {
+                messages.push(self.describe(job, i + 1 == len));
+            }
+        }
+
+        self.jobs.retain(|job| !job.is_done());
+        messages
+    }
+}
@@ -1,156 +1,1132 @@
-extern crate itertools;
+extern crate libc;
 
-use itertools::Itertools;
 use std::collections::HashMap;
-use std::io::{self, Write};
-use std::process::{Command, ExitStatus};
+use std::fs::{File, OpenOptions};
+use std::io::{self, IsTerminal, Read, Write};
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::thread;
 
+mod builtins;
+mod complete;
+mod dirs;
+mod editor;
+mod frecency;
+mod glob;
+mod history;
+mod jobs;
 mod parser;
+mod signals;
+mod tilde;
+mod variables;
+
+use builtins::Registry;
+use dirs::Dirs;
+use editor::Editor;
+use history::History;
+use jobs::{Job, Jobs, Pid, Termination};
 use parser::*;
+use variables::Variables;
 
-// TODO(tsion): Use the readline library.
-fn prompt(line: &mut String) -> io::Result<usize> {
-    let current_dir = try!(std::env::current_dir());
-    print!("{}> ", current_dir.display());
-    try!(io::stdout().flush());
-    io::stdin().read_line(line)
+fn prompt(variables: &Variables) -> String {
+    match dirs::logical_cwd(variables) {
+        Ok(current_dir) => format!("{}> ", current_dir.display()),
+        Err(_) => String::from("> "),
+    }
 }
 
-struct Builtin {
-    name: &'static str,
-    min_args: usize,
-    max_args: usize,
-    func: fn(&[String]) -> i32,
+/// The prompt for the lines after the first of a command which carries on over several lines,
+/// lined up with the end of the main prompt.
+fn continuation_prompt(prompt: &str) -> String {
+    format!("{:>1$}", "> ", prompt.chars().count())
 }
 
-fn result_to_exit_code(cmd: &'static str, result: io::Result<()>) -> i32 {
-    match result {
-        Ok(()) => 0,
+/// Load the history file, falling back to history which isn't saved if it can't be read.
+fn load_history() -> History {
+    let path = match history::default_path() {
+        Some(path) => path,
+        None => return History::in_memory(),
+    };
+
+    match History::load(path.clone()) {
+        Ok(history) => history,
         Err(e) => {
-            writeln!(&mut io::stderr(), "shroom: {}: {}", cmd, e).unwrap();
-            1
+            writeln!(&mut io::stderr(), "shroom: can't load history from {}: {}",
+                     path.display(), e).unwrap();
+            History::in_memory()
         },
     }
 }
 
-fn builtin_cd(args: &[String]) -> i32 {
-    if let Some(path) = args.get(0) {
-        result_to_exit_code("cd", std::env::set_current_dir(path))
-    } else if let Some(home) = std::env::home_dir() {
-        result_to_exit_code("cd", std::env::set_current_dir(home))
-    } else {
-        writeln!(&mut io::stderr(), "shroom: cd: couldn't find home dir").unwrap();
-        1
+/// Load the database of directories changed to, falling back to one which isn't saved if it
+/// can't be read.
+fn load_visited_dirs() -> frecency::Database {
+    let path = match frecency::default_path() {
+        Some(path) => path,
+        None => return frecency::Database::in_memory(),
+    };
+
+    match frecency::Database::load(path.clone()) {
+        Ok(database) => database,
+        Err(e) => {
+            writeln!(&mut io::stderr(), "shroom: can't load directories from {}: {}",
+                     path.display(), e).unwrap();
+            frecency::Database::in_memory()
+        },
     }
 }
 
-fn builtin_exit(args: &[String]) -> i32 {
-    if let Some(exit_code_str) = args.get(0) {
-        match exit_code_str.parse() {
-            Ok(exit_code) => std::process::exit(exit_code),
+/// The system-wide config file, which is run before the user's.
+const SYSTEM_CONFIG_PATH: &str = "/etc/shroom/config.shr";
+
+/// The user's config file, `~/.config/shroom/config.shr`, or under `$XDG_CONFIG_HOME` if it is
+/// set.
+fn user_config_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => std::env::home_dir()?.join(".config"),
+    };
+    Some(config_dir.join("shroom/config.shr"))
+}
+
+/// Run the system-wide and user config files for an interactive shell. Missing files are
+/// skipped.
+fn run_config(shell: &mut Shell) {
+    let paths = Some(PathBuf::from(SYSTEM_CONFIG_PATH)).into_iter().chain(user_config_path());
+    for path in paths {
+        match std::fs::read_to_string(&path) {
+            Ok(source) => {
+                run_source(shell, &path.to_string_lossy(), &source);
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
             Err(e) => {
-                writeln!(&mut io::stderr(), "shroom: exit: can't parse exit code: {}", e).unwrap();
-                1
+                writeln!(&mut io::stderr(), "shroom: can't read {}: {}", path.display(), e)
+                    .unwrap();
             },
         }
-    } else {
-        std::process::exit(0);
     }
 }
 
-fn execute(ast: &Ast) -> i32 {
-    let mut builtins = HashMap::new();
+/// The standard streams a command runs with, after pipes and redirections have been applied.
+struct Streams {
+    stdin: File,
+    stdout: File,
+    stderr: File,
+}
 
-    builtins.insert("cd", Builtin {
-        name: "cd",
-        min_args: 0,
-        max_args: 1,
-        func: builtin_cd,
-    });
+impl Streams {
+    /// Copies of the shell's own standard streams.
+    fn inherit() -> io::Result<Streams> {
+        fn dup<F: AsFd>(stream: F) -> io::Result<File> {
+            Ok(File::from(stream.as_fd().try_clone_to_owned()?))
+        }
+
+        Ok(Streams {
+            stdin: dup(io::stdin())?,
+            stdout: dup(io::stdout())?,
+            stderr: dup(io::stderr())?,
+        })
+    }
+
+    fn get(&self, fd: u32) -> Option<&File> {
+        match fd {
+            0 => Some(&self.stdin),
+            1 => Some(&self.stdout),
+            2 => Some(&self.stderr),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, fd: u32, file: File) {
+        match fd {
+            0 => self.stdin = file,
+            1 => self.stdout = file,
+            2 => self.stderr = file,
+            _ => unreachable!("only the standard streams can be redirected"),
+        }
+    }
+
+    /// Apply the redirections in order, so that `> file 2>&1` sends both streams to `file`.
+    fn redirect(&mut self, shell: &mut Shell, redirections: &[Redirection])
+                -> Result<(), String> {
+        for redirection in redirections {
+            if self.get(redirection.fd).is_none() {
+                return Err(format!("can't redirect file descriptor {}", redirection.fd));
+            }
+
+            let file = match redirection.target {
+                RedirectTarget::Input(ref word) => {
+                    let path = evaluate_single_word(shell, word)?;
+                    File::open(&path)
+                        .map_err(|e| format!("{}: {}", path, e))?
+                },
+
+                RedirectTarget::Output(ref word) => {
+                    let path = evaluate_single_word(shell, word)?;
+                    File::create(&path)
+                        .map_err(|e| format!("{}: {}", path, e))?
+                },
+
+                RedirectTarget::Append(ref word) => {
+                    let path = evaluate_single_word(shell, word)?;
+                    OpenOptions::new().append(true).create(true).open(&path)
+                        .map_err(|e| format!("{}: {}", path, e))?
+                },
+
+                RedirectTarget::Duplicate(target_fd) => {
+                    let target = self.get(target_fd).ok_or_else(|| {
+                        format!("can't duplicate file descriptor {}", target_fd)
+                    })?;
+                    target.try_clone().map_err(|e| e.to_string())?
+                },
+            };
+
+            self.set(redirection.fd, file);
+        }
+
+        Ok(())
+    }
+}
+
+/// The state of the shell which persists between commands.
+struct Shell {
+    variables: Variables,
+    jobs: Jobs,
 
-    builtins.insert("exit", Builtin {
-        name: "exit",
-        min_args: 0,
-        max_args: 1,
-        func: builtin_exit,
+    /// Whether the shell is reading commands from a terminal, so it should report on jobs and
+    /// failed commands.
+    interactive: bool,
+
+    /// How the last command of the most recently executed pipeline ended, kept so that signal
+    /// deaths can be reported by name.
+    last_termination: Termination,
+
+    /// Set by `break`, `continue` or `return` to skip the rest of the innermost loop's body or
+    /// function.
+    control: Option<Control>,
+
+    /// How many loops are running in the current function, so `break` and `continue` outside
+    /// of one can be rejected.
+    loop_depth: usize,
+
+    /// How many functions are running, so `return` outside of one can be rejected.
+    function_depth: usize,
+
+    /// How many command substitutions are running, so `exit` inside one only ends the
+    /// substitution rather than the whole shell.
+    capture_depth: usize,
+
+    functions: HashMap<String, Rc<Ast>>,
+
+    /// The command each alias stands for, as source text to be parsed when it's used.
+    aliases: HashMap<String, String>,
+
+    /// The text each abbreviation expands to when it's typed as a command in the line editor.
+    abbreviations: HashMap<String, String>,
+
+    builtins: Registry,
+    dirs: Dirs,
+
+    /// The directories changed to, for `z` to jump to. Only saved in interactive shells.
+    visited_dirs: frecency::Database,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Control {
+    Break,
+    Continue,
+    Return,
+
+    /// Set by `exit` in a command substitution, to skip the rest of the substitution.
+    Exit,
+}
+
+impl Shell {
+    fn new() -> Shell {
+        let mut variables = Variables::new();
+        dirs::init(&mut variables);
+        variables.set("status", vec![String::from("0")]);
+        variables.set("pipestatus", vec![String::from("0")]);
+        Shell {
+            variables,
+            jobs: Jobs::new(),
+            interactive: false,
+            last_termination: Termination::Exited(0),
+            control: None,
+            loop_depth: 0,
+            function_depth: 0,
+            capture_depth: 0,
+            functions: HashMap::new(),
+            aliases: HashMap::new(),
+            abbreviations: HashMap::new(),
+            builtins: Registry::new(),
+            dirs: Dirs::new(),
+            visited_dirs: frecency::Database::in_memory(),
+        }
+    }
+
+    /// Whether the last command run was killed by Ctrl-C, which stops whatever ran it as well.
+    fn interrupted(&self) -> bool {
+        matches!(self.last_termination, Termination::Signaled { signal: libc::SIGINT, .. })
+    }
+
+    /// The message printed after a command fails, taken from the `status_message` variable if
+    /// it's set. The placeholders `{status}`, `{pipestatus}` and `{reason}` are replaced, and an
+    /// empty message turns reporting off.
+    fn status_message(&self, exit_code: i32) -> Option<String> {
+        let format = self.variables.get("status_message")
+            .map(|value| value.join(" "))
+            .unwrap_or_else(|| String::from("shroom: {reason}"));
+
+        if format.is_empty() {
+            return None;
+        }
+
+        let reason = match self.last_termination {
+            Termination::Signaled { signal, core_dumped } if 128 + signal == exit_code => {
+                format!("terminated by {}{}", signals::name(signal),
+                        if core_dumped { " (core dumped)" } else { "" })
+            },
+            _ => format!("exit code: {}", exit_code),
+        };
+
+        let pipestatus = self.variables.get("pipestatus").unwrap_or_default().join(" ");
+
+        Some(format.replace("{status}", &exit_code.to_string())
+                   .replace("{pipestatus}", &pipestatus)
+                   .replace("{reason}", &reason))
+    }
+}
+
+/// Run commands with their stdout captured, for a command substitution. The output is returned
+/// with any trailing newlines removed. A `$(...)` uses it as a single value, even if it has
+/// several lines or is empty, and only a `$@(...)` splits it into lines.
+fn capture_output(shell: &mut Shell, ast: &Ast) -> Result<String, String> {
+    let (mut reader, writer) = io::pipe()
+        .map_err(|e| format!("can't create pipe: {}", e))?;
+    let saved_stdout = io::stdout().as_fd().try_clone_to_owned()
+        .map_err(|e| format!("can't save stdout: {}", e))?;
+
+    // Point the shell's own stdout at the pipe while the commands run, so everything they
+    // write there is captured, including output from builtins. The output is read on another
+    // thread so a command writing more than fits in the pipe doesn't block forever.
+    let _ = io::stdout().flush();
+    unsafe { libc::dup2(writer.as_raw_fd(), libc::STDOUT_FILENO) };
+    drop(writer);
+    let reading = thread::spawn(move || {
+        let mut output = vec![];
+        reader.read_to_end(&mut output).map(|_| output)
     });
 
-    match *ast {
-        Ast::Empty => 0,
+    shell.capture_depth += 1;
+    execute(shell, ast);
+    shell.capture_depth -= 1;
+    if shell.control == Some(Control::Exit) {
+        shell.control = None;
+    }
 
-        Ast::Call { ref command, ref args } => {
-            // Evaluate argument expressions.
-            let evaluated_args: Vec<String> = args.iter().map(|arg| {
-                arg.iter().map(|expr| {
-                    match *expr {
-                        Expr::Text(ref text) => text,
-                    }
-                }).join("")
-            }).collect();
+    let _ = io::stdout().flush();
+    unsafe { libc::dup2(saved_stdout.as_raw_fd(), libc::STDOUT_FILENO) };
+    drop(saved_stdout);
 
-            if let Some(builtin) = builtins.get(&command[..]) {
-                if args.len() < builtin.min_args {
-                    writeln!(&mut io::stderr(), "shroom: {}: not enough arguments",
-                             builtin.name).unwrap();
-                    1
-                } else if args.len() > builtin.max_args {
-                    writeln!(&mut io::stderr(), "shroom: {}: too many arguments",
-                             builtin.name).unwrap();
-                    1
-                } else {
-                    (builtin.func)(&evaluated_args)
+    let output = reading.join().unwrap()
+        .map_err(|e| format!("can't read command output: {}", e))?;
+    let output = String::from_utf8_lossy(&output);
+    Ok(String::from(output.trim_end_matches('\n')))
+}
+
+/// Build the string from the parts of a double-quoted string, joining the values of a variable
+/// with spaces. An empty or undefined variable is just left out.
+fn interpolate(shell: &mut Shell, parts: &[Expr]) -> Result<String, String> {
+    let mut string = String::new();
+    for part in parts {
+        match *part {
+            Expr::Text(ref text) => string.push_str(text),
+            Expr::Variable(ref name) => {
+                string.push_str(&shell.variables.get(name).unwrap_or_default().join(" "));
+            },
+            Expr::CommandSubstitution(ref ast) => string.push_str(&capture_output(shell, ast)?),
+            _ => unreachable!(),
+        }
+    }
+    Ok(string)
+}
+
+/// Whether a word has a wildcard in it, including inside braces.
+fn is_pattern(word: &[Expr]) -> bool {
+    word.iter().any(|expr| match *expr {
+        Expr::Glob(_) => true,
+        Expr::Brace(ref alternatives) => alternatives.iter().any(|word| is_pattern(word)),
+        _ => false,
+    })
+}
+
+/// Expand the parts of a word to every combination of their values. If the word is a
+/// `pattern`, everything but the wildcards is escaped.
+fn expand_parts(shell: &mut Shell, word: &[Expr], pattern: bool) -> Result<Vec<String>, String> {
+    let mut results = vec![String::new()];
+
+    for expr in word {
+        let values = match *expr {
+            Expr::Text(ref text) => vec![text.clone()],
+            Expr::Variable(ref name) => shell.variables.get(name).unwrap_or_default(),
+            Expr::Glob(ref pattern) => vec![pattern.clone()],
+            Expr::Tilde(ref name) => vec![tilde::expand(name, &shell.variables)?],
+            Expr::CommandSubstitution(ref ast) => vec![capture_output(shell, ast)?],
+            Expr::LineSubstitution(ref ast) => {
+                capture_output(shell, ast)?.lines().map(String::from).collect()
+            },
+            Expr::Quoted(ref parts) => vec![interpolate(shell, parts)?],
+            Expr::Brace(ref alternatives) => {
+                let mut values = vec![];
+                for alternative in alternatives {
+                    values.extend(expand_parts(shell, alternative, pattern)?);
+                }
+                values
+            },
+        };
+
+        // Only the wildcards themselves are special in a pattern, not quoted text or variables.
+        let values: Vec<String> = match *expr {
+            Expr::Text(_) | Expr::Variable(_) | Expr::Tilde(_) | Expr::CommandSubstitution(_) |
+            Expr::LineSubstitution(_) | Expr::Quoted(_) if pattern => {
+                values.iter().map(|value| glob::escape(value)).collect()
+            },
+            _ => values,
+        };
+
+        results = results.iter().flat_map(|prefix| {
+            values.iter().map(move |value| format!("{}{}", prefix, value))
+        }).collect();
+    }
+
+    Ok(results)
+}
+
+/// Evaluate a word to the arguments it expands to. Each part of the word expands to a list of
+/// strings and the word expands to every combination of them, so `a$xs` gives `a1 a2` when `xs`
+/// is `1 2`, and a word containing an empty or undefined variable expands to nothing at all.
+/// Braces work the same way, so `{a,b}{1,2}` gives `a1 a2 b1 b2`.
+///
+/// A word with a wildcard in it is a pattern, and expands to the sorted paths it matches. It's
+/// an error for a pattern to match nothing.
+fn evaluate_word(shell: &mut Shell, word: &[Expr]) -> Result<Vec<String>, String> {
+    let pattern = is_pattern(word);
+    let results = expand_parts(shell, word, pattern)?;
+    if !pattern {
+        return Ok(results);
+    }
+
+    let mut paths = vec![];
+    for pattern in results {
+        let matches = glob::expand(&pattern);
+        if matches.is_empty() {
+            return Err(format!("no matches for wildcard `{}`", pattern));
+        }
+        paths.extend(matches);
+    }
+    Ok(paths)
+}
+
+/// Evaluate a word which must expand to exactly one string, like a redirection target.
+fn evaluate_single_word(shell: &mut Shell, word: &[Expr]) -> Result<String, String> {
+    let mut values = evaluate_word(shell, word)?;
+    if values.len() == 1 {
+        Ok(values.pop().unwrap())
+    } else {
+        Err(format!("expected a single value but got {}", values.len()))
+    }
+}
+
+/// Evaluate argument expressions.
+fn evaluate_args(shell: &mut Shell, args: &[Vec<Expr>]) -> Result<Vec<String>, String> {
+    let mut evaluated = vec![];
+    for arg in args {
+        evaluated.extend(evaluate_word(shell, arg)?);
+    }
+    Ok(evaluated)
+}
+
+/// The state of one stage of a pipeline after it has been started.
+enum Stage {
+    /// An external command or forked builtin which is still running.
+    Running(Pid),
+
+    /// A builtin, or a command which failed to start, with its exit code.
+    Finished(i32),
+}
+
+/// Execute a statement, setting `$status` to its exit code and returning it.
+fn execute(shell: &mut Shell, ast: &Ast) -> i32 {
+    let exit_code = match *ast {
+        Ast::Empty => return 0,
+        Ast::Call { .. } => execute_pipeline(shell, ast, std::slice::from_ref(ast), false),
+        Ast::Pipeline(ref calls) => execute_pipeline(shell, ast, calls, false),
+
+        Ast::Background(ref pipeline) => {
+            match **pipeline {
+                Ast::Pipeline(ref calls) => execute_pipeline(shell, pipeline, calls, true),
+                _ => execute_pipeline(shell, pipeline, std::slice::from_ref(&**pipeline), true),
+            }
+        },
+
+        // Statements after a `break`, `continue` or `return` are skipped until the loop or
+        // function handles it, and so is everything after a command interrupted by Ctrl-C.
+        Ast::Sequence(ref statements) => {
+            let mut exit_code = 0;
+            for statement in statements {
+                if shell.control.is_some() {
+                    break;
+                }
+                exit_code = execute(shell, statement);
+                if shell.interrupted() {
+                    break;
                 }
+            }
+            exit_code
+        },
+
+        Ast::And(ref left, ref right) => {
+            match execute(shell, left) {
+                0 if shell.control.is_none() => execute(shell, right),
+                exit_code => exit_code,
+            }
+        },
+
+        Ast::Or(ref left, ref right) => {
+            match execute(shell, left) {
+                exit_code if exit_code == 0 || shell.control.is_some() || shell.interrupted() => {
+                    exit_code
+                },
+                _ => execute(shell, right),
+            }
+        },
+
+        Ast::Function { ref name, ref body } => {
+            shell.functions.insert(name.clone(), Rc::new((**body).clone()));
+            0
+        },
+
+        Ast::If { ref condition, ref then, ref otherwise } => {
+            let exit_code = execute(shell, condition);
+            if shell.control.is_some() || shell.interrupted() {
+                exit_code
+            } else if exit_code == 0 {
+                execute(shell, then)
+            } else if let Some(ref otherwise) = *otherwise {
+                execute(shell, otherwise)
             } else {
-                match Command::new(command).args(&evaluated_args).status() {
-                    Ok(exit_status) => {
-                        #[cfg(unix)]
-                        fn exit_signal(exit_status: &ExitStatus) -> Option<i32> {
-                            use std::os::unix::process::ExitStatusExt;
-                            exit_status.signal()
-                        }
+                0
+            }
+        },
 
-                        #[cfg(not(unix))]
-                        fn exit_signal(_exit_status: &ExitStatus) -> Option<i32> {
-                            None
-                        }
+        Ast::While { ref condition, ref body } => {
+            shell.loop_depth += 1;
+            let mut exit_code = 0;
+            while execute(shell, condition) == 0 && end_iteration(shell) {
+                exit_code = execute(shell, body);
+                if !end_iteration(shell) {
+                    break;
+                }
+            }
+            shell.loop_depth -= 1;
+            exit_code
+        },
+
+        Ast::For { ref variable, ref items, ref body } => {
+            match evaluate_args(shell, items) {
+                Err(e) => {
+                    writeln!(&mut io::stderr(), "shroom: {}", e).unwrap();
+                    1
+                },
+
+                Ok(_) if variables::is_read_only(variable) => {
+                    writeln!(&mut io::stderr(), "shroom: for: {} is read-only", variable)
+                        .unwrap();
+                    1
+                },
 
-                        if let Some(code) = exit_status.code() {
-                            code
-                        } else if let Some(signal) = exit_signal(&exit_status) {
-                            128 + signal
-                        } else {
-                            127
+                Ok(items) => {
+                    shell.loop_depth += 1;
+                    let mut exit_code = 0;
+                    for item in items {
+                        shell.variables.set(variable, vec![item]);
+                        exit_code = execute(shell, body);
+                        if !end_iteration(shell) {
+                            break;
                         }
-                    },
+                    }
+                    shell.loop_depth -= 1;
+                    exit_code
+                },
+            }
+        },
+    };
+
+    shell.variables.set("status", vec![exit_code.to_string()]);
+    exit_code
+}
+
+/// Handle any `break` or `continue` at the end of a loop iteration, returning whether the loop
+/// should keep going. A `return` is left for the function to handle, and an `exit` for the
+/// command substitution. A command interrupted by Ctrl-C stops the loop too.
+fn end_iteration(shell: &mut Shell) -> bool {
+    let keep_going = match shell.control {
+        None => true,
+        Some(Control::Continue) => {
+            shell.control = None;
+            true
+        },
+        Some(Control::Break) => {
+            shell.control = None;
+            false
+        },
+        Some(Control::Return) | Some(Control::Exit) => false,
+    };
+    keep_going && !shell.interrupted()
+}
+
+/// The most function calls which can be running at once, so runaway recursion is an error
+/// rather than overflowing the stack.
+const MAX_FUNCTION_DEPTH: usize = 128;
+
+/// Run a function's body in the shell itself with the streams as its standard streams, in a
+/// new variable scope where `$argv` holds the arguments.
+fn call_function(shell: &mut Shell, name: &str, body: &Ast, args: Vec<String>, streams: Streams)
+                 -> i32 {
+    if shell.function_depth >= MAX_FUNCTION_DEPTH {
+        let _ = writeln!(&streams.stderr, "shroom: {}: functions called more than {} deep",
+                         name, MAX_FUNCTION_DEPTH);
+        return 1;
+    }
+
+    let _ = io::stdout().flush();
+    let saved = match with_streams(&streams) {
+        Ok(saved) => saved,
+        Err(e) => {
+            let _ = writeln!(&streams.stderr, "shroom: {}", e);
+            return 1;
+        },
+    };
+    drop(streams);
+
+    // Loops outside the function can't be broken out of from inside it.
+    let loop_depth = mem::replace(&mut shell.loop_depth, 0);
+    shell.function_depth += 1;
+    shell.variables.push_scope();
+    shell.variables.set_local("argv", args);
+
+    let exit_code = execute(shell, body);
+
+    shell.variables.pop_scope();
+    shell.function_depth -= 1;
+    shell.loop_depth = loop_depth;
+    if shell.control == Some(Control::Return) {
+        shell.control = None;
+    }
+
+    let _ = io::stdout().flush();
+    restore_streams(saved);
+    exit_code
+}
+
+/// Point the shell's own standard streams at `streams`, returning copies of the old ones for
+/// `restore_streams`.
+fn with_streams(streams: &Streams) -> io::Result<[OwnedFd; 3]> {
+    let saved = [
+        io::stdin().as_fd().try_clone_to_owned()?,
+        io::stdout().as_fd().try_clone_to_owned()?,
+        io::stderr().as_fd().try_clone_to_owned()?,
+    ];
+    for (fd, file) in [&streams.stdin, &streams.stdout, &streams.stderr].iter().enumerate() {
+        unsafe { libc::dup2(file.as_raw_fd(), fd as RawFd) };
+    }
+    Ok(saved)
+}
+
+fn restore_streams(saved: [OwnedFd; 3]) {
+    for (fd, file) in saved.iter().enumerate() {
+        unsafe { libc::dup2(file.as_raw_fd(), fd as RawFd) };
+    }
+}
+
+/// A call's command word, arguments and redirections.
+type CallParts = (Vec<Expr>, Vec<Vec<Expr>>, Vec<Redirection>);
+
+/// If the command word is the name of an alias, replace it with the alias's command, putting
+/// the alias's arguments and redirections before the call's own. This repeats while the new
+/// command is also an alias, except that an alias is never expanded inside itself, so
+/// `alias ls = ls -F` works. Returns `None` if the command isn't an alias.
+fn expand_alias(shell: &Shell, command: &[Expr], args: &[Vec<Expr>],
+                redirections: &[Redirection]) -> Option<CallParts> {
+    let mut expanded: Vec<String> = vec![];
+    let mut call: Option<CallParts> = None;
+
+    loop {
+        let name = match *call.as_ref().map_or(command, |call| &call.0[..]) {
+            [Expr::Text(ref name)] if !expanded.contains(name) => name.clone(),
+            _ => break,
+        };
+        let (alias_command, alias_args, alias_redirections) = match shell.aliases.get(&name)
+                .map(|value| Parser::new(value).parse()) {
+            Some(Ok(Ast::Call { command, args, redirections })) => (command, args, redirections),
+            _ => break,
+        };
+
+        let (_, rest_args, rest_redirections) = call.take().unwrap_or_else(|| {
+            (command.to_vec(), args.to_vec(), redirections.to_vec())
+        });
+        call = Some((alias_command,
+                     alias_args.into_iter().chain(rest_args).collect(),
+                     alias_redirections.into_iter().chain(rest_redirections).collect()));
+        expanded.push(name);
+    }
+
+    call
+}
+
+/// Run a builtin to completion or spawn an external command. The streams are dropped before
+/// returning so that the commands on the other ends of any pipes see EOF once this one exits.
+///
+/// With job control on, external commands are put in the process group `pgid`, or a new one if
+/// it's 0, and a `foreground` command takes the terminal as soon as it starts. If `fork` is set,
+/// a builtin or function runs in a copy of the shell, like an external command. `next_stdin` is
+/// the read end of the pipe this command writes to, which the copy closes.
+fn start_call(shell: &mut Shell, call: &Ast, mut streams: Streams, next_stdin: &mut Option<File>,
+              pgid: Pid, foreground: bool, fork: bool) -> Stage {
+    let (command, args, redirections) = match *call {
+        Ast::Call { ref command, ref args, ref redirections } => (command, args, redirections),
+        _ => unreachable!("pipeline stages are always calls"),
+    };
+
+    let expanded = expand_alias(shell, command, args, redirections);
+    let (command, args, redirections) = match expanded {
+        Some((ref command, ref args, ref redirections)) => {
+            (&command[..], &args[..], &redirections[..])
+        },
+        None => (&command[..], &args[..], &redirections[..]),
+    };
+
+    if let Err(e) = streams.redirect(shell, redirections) {
+        let _ = writeln!(streams.stderr, "shroom: {}", e);
+        return Stage::Finished(1);
+    }
+
+    // The command word can expand to several values, with the rest becoming arguments.
+    let evaluated = evaluate_word(shell, command).and_then(|mut evaluated| {
+        if evaluated.is_empty() {
+            return Err(String::from("the command expanded to nothing"));
+        }
+        evaluated.extend(evaluate_args(shell, args)?);
+        Ok(evaluated)
+    });
+    let mut evaluated_args = match evaluated {
+        Ok(evaluated) => evaluated,
+        Err(e) => {
+            let _ = writeln!(streams.stderr, "shroom: {}", e);
+            return Stage::Finished(1);
+        },
+    };
+    let command = evaluated_args.remove(0);
+
+    let in_shell = shell.builtins.get(&command).is_some() || shell.functions.contains_key(&command);
+    if in_shell && fork {
+        return fork_call(shell, &command, evaluated_args, streams, next_stdin, pgid, foreground);
+    }
+
+    if let Some(builtin) = shell.builtins.get(&command) {
+        Stage::Finished(builtins::run(shell, &*builtin, &evaluated_args, &mut streams))
+    } else if let Some(body) = shell.functions.get(&command).cloned() {
+        Stage::Finished(call_function(shell, &command, &body, evaluated_args, streams))
+    } else {
+        let mut cmd = Command::new(&command);
+        cmd.args(&evaluated_args);
+
+        for name in shell.variables.removed() {
+            cmd.env_remove(name);
+        }
+        cmd.envs(shell.variables.exported());
+
+        let mut stderr = match streams.stderr.try_clone() {
+            Ok(stderr) => stderr,
+            Err(e) => {
+                writeln!(&mut io::stderr(), "shroom: {}", e).unwrap();
+                return Stage::Finished(1);
+            },
+        };
+
+        cmd.stdin(Stdio::from(streams.stdin))
+           .stdout(Stdio::from(streams.stdout))
+           .stderr(Stdio::from(streams.stderr));
+
+        let terminal_fd = shell.jobs.terminal_fd();
+        if terminal_fd.is_some() {
+            cmd.process_group(pgid);
+        }
 
-                    Err(e) => {
-                        writeln!(&mut io::stderr(), "shroom: {}: {}", command, e).unwrap();
-                        127
-                    },
+        unsafe {
+            cmd.pre_exec(move || {
+                // Take the terminal in the child as well as the parent, so it's in the
+                // foreground before the program can try to read from it.
+                if let Some(fd) = terminal_fd {
+                    let pgid = if pgid == 0 { libc::getpid() } else { pgid };
+                    libc::setpgid(0, pgid);
+                    if foreground {
+                        libc::tcsetpgrp(fd, pgid);
+                    }
                 }
+                signals::reset_for_child();
+                Ok(())
+            });
+        }
+
+        match cmd.spawn() {
+            Ok(child) => Stage::Running(child.id() as Pid),
+            Err(e) => {
+                let _ = writeln!(stderr, "shroom: {}: {}", command, e);
+                Stage::Finished(127)
+            },
+        }
+    }
+}
+
+/// Run a builtin or function in a forked copy of the shell, so it runs at the same time as the
+/// rest of its pipeline. Job control is off in the copy, and it ends once the command does.
+fn fork_call(shell: &mut Shell, command: &str, args: Vec<String>, mut streams: Streams,
+             next_stdin: &mut Option<File>, pgid: Pid, foreground: bool) -> Stage {
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+    let terminal_fd = shell.jobs.terminal_fd();
+
+    match unsafe { libc::fork() } {
+        -1 => {
+            let e = io::Error::last_os_error();
+            let _ = writeln!(streams.stderr, "shroom: {}: can't fork: {}", command, e);
+            Stage::Finished(1)
+        },
+
+        0 => {
+            unsafe {
+                if let Some(fd) = terminal_fd {
+                    let pgid = if pgid == 0 { libc::getpid() } else { pgid };
+                    libc::setpgid(0, pgid);
+                    if foreground {
+                        libc::tcsetpgrp(fd, pgid);
+                    }
+                }
+                // Stop when the next command in the pipeline stops reading, like other commands.
+                libc::signal(libc::SIGPIPE, libc::SIG_DFL);
             }
+            signals::reset_for_child();
+            shell.jobs.disable_job_control();
+            // Forking ignores close-on-exec, so without this the copy would hold its own output
+            // pipe open for reading and never see the next command stop reading it.
+            drop(next_stdin.take());
+
+            let exit_code = match shell.builtins.get(command) {
+                Some(builtin) => builtins::run(shell, &*builtin, &args, &mut streams),
+                None => {
+                    let body = shell.functions[command].clone();
+                    call_function(shell, command, &body, args, streams)
+                },
+            };
+            let _ = io::stdout().flush();
+            unsafe { libc::_exit(exit_code) }
         },
+
+        pid => Stage::Running(pid),
     }
 }
 
-fn main() {
-    let mut line = String::new();
+/// Start every command in the pipeline with its stdout connected to the next command's stdin,
+/// then wait for all of them, or add them to the job table as a job if it's a `background`
+/// pipeline. Returns the exit code of the last command, or 0 for a background job.
+///
+/// Builtins and functions at the end of a foreground pipeline run in the shell itself, so they
+/// finish before the pipeline is waited on. Earlier ones, and any in a background job, are forked
+/// so they can run alongside the other commands, which means any variables they set are lost.
+fn execute_pipeline(shell: &mut Shell, ast: &Ast, calls: &[Ast], background: bool) -> i32 {
+    let mut job = Job::new(ast.to_string());
+    let mut next_stdin = None;
+
+    for (i, call) in calls.iter().enumerate() {
+        let mut streams = match Streams::inherit() {
+            Ok(streams) => streams,
+            Err(e) => {
+                writeln!(&mut io::stderr(), "shroom: {}", e).unwrap();
+                job.add_finished(1);
+                break;
+            },
+        };
+
+        // The read end of the pipe the previous stage writes to, if any.
+        if let Some(stdin) = next_stdin.take() {
+            streams.stdin = stdin;
+        }
+
+        if i + 1 < calls.len() {
+            match io::pipe() {
+                Ok((reader, writer)) => {
+                    next_stdin = Some(File::from(OwnedFd::from(reader)));
+                    streams.stdout = File::from(OwnedFd::from(writer));
+                },
+                Err(e) => {
+                    writeln!(&mut io::stderr(), "shroom: can't create pipe: {}", e).unwrap();
+                    job.add_finished(1);
+                    break;
+                },
+            }
+        }
+
+        let pgid = job.pgid;
+        let fork = background || i + 1 < calls.len();
+        match start_call(shell, call, streams, &mut next_stdin, pgid, !background, fork) {
+            Stage::Running(pid) => {
+                job.add_process(pid);
+                // Also set the process group from the parent, to avoid racing with the child.
+                if shell.jobs.job_control() {
+                    unsafe { libc::setpgid(pid, job.pgid) };
+                }
+            },
+            Stage::Finished(exit_code) => job.add_finished(exit_code),
+        }
+    }
+
+    if background && !job.is_done() {
+        let pgid = job.pgid;
+        let id = shell.jobs.add(job);
+        if shell.interactive {
+            eprintln!("[{}] {}", id, pgid);
+        }
+        shell.variables.set("last_pid", vec![pgid.to_string()]);
+        return 0;
+    }
+
+    let job = shell.jobs.run_foreground(job, false);
+    let exit_code = job.exit_code();
+
+    // A builtin or function at the end of the pipeline which ran commands itself, like `fg`,
+    // leaves how the last of them ended. Keep that if it passed on their exit code, so a job
+    // killed by a signal is still reported as such.
+    let termination = job.termination();
+    let passed_on = termination == Termination::Exited(shell.last_termination.exit_code());
+    if !(job.ends_in_shell() && passed_on) {
+        shell.last_termination = termination;
+    }
+    let pipestatus = job.exit_codes().iter().map(|code| code.to_string()).collect();
+    shell.variables.set("pipestatus", pipestatus);
+
+    exit_code
+}
+
+/// Parse and execute a whole script or `-c` command, returning the exit code of the last
+/// command run.
+fn run_source(shell: &mut Shell, name: &str, source: &str) -> i32 {
+    match Parser::new(source).parse() {
+        Ok(ast) => execute(shell, &ast),
+        Err(parse_error) => {
+            let rendered = parse_error.render(source, Some(name), 1);
+            writeln!(&mut io::stderr(), "shroom: {}", rendered).unwrap();
+            2
+        },
+    }
+}
+
+/// Read a line from stdin one byte at a time, so that nothing after the newline is taken from
+/// commands run from the script which read the rest of stdin themselves. Returns `None` at EOF.
+fn read_line_unbuffered() -> io::Result<Option<String>> {
+    let mut stdin = File::from(io::stdin().as_fd().try_clone_to_owned()?);
+    let mut line = vec![];
+    let mut byte = [0];
+    while stdin.read(&mut byte)? == 1 {
+        line.push(byte[0]);
+        if byte[0] == b'\n' {
+            break;
+        }
+    }
+
+    if line.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+    }
+}
+
+/// Read and execute commands from stdin one line at a time until EOF. If the shell is
+/// interactive, lines are read with the line editor and failures are reported. Returns the exit
+/// code of the last command.
+fn run_interactive(shell: &mut Shell) -> i32 {
+    let interactive = shell.interactive;
+    let mut editor = if interactive { Some(Editor::new(load_history())) } else { None };
+    let mut exit_code = 0;
+    // The line each command starts on, for parse errors in a script read from stdin.
+    let mut line_number = 1;
+
     loop {
-        prompt(&mut line).unwrap();
+        if interactive {
+            for message in shell.jobs.reap() {
+                eprintln!("{}", message);
+            }
+        }
+
+        let prompt = prompt(&shell.variables);
+        let mut source = String::new();
+
+        // Keep reading lines until they make a complete command, so that quotes, blocks and lines
+        // ending in operators like `|` can carry on over several lines.
+        let parsed = loop {
+            let input = match editor {
+                Some(ref mut editor) => {
+                    let prompt = if source.is_empty() {
+                        prompt.clone()
+                    } else {
+                        continuation_prompt(&prompt)
+                    };
+                    let functions: Vec<&str> =
+                        shell.functions.keys().map(|name| &name[..]).collect();
+                    editor.read_line(&prompt, |line| {
+                        complete::complete(line, &shell.builtins, &functions, &shell.variables)
+                    }, |line| {
+                        complete::abbreviation(line, &shell.abbreviations, &shell.variables)
+                    })
+                },
+                None => read_line_unbuffered(),
+            };
+
+            match input {
+                Ok(Some(line)) => {
+                    source.push_str(&line);
+                    if !source.ends_with('\n') {
+                        source.push('\n');
+                    }
+                },
+
+                Ok(None) if source.is_empty() => {
+                    if interactive {
+                        println!();
+                    }
+                    return exit_code;
+                },
+
+                // Report whatever's missing from the incomplete command.
+                Ok(None) => break Parser::new(&source).parse(),
+
+                // `Ctrl-C` abandons the whole command, not just the line being edited.
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                    source.clear();
+                    continue;
+                },
+
+                Err(e) => {
+                    writeln!(&mut io::stderr(), "shroom: can't read input: {}", e).unwrap();
+                    return 1;
+                },
+            }
+
+            match Parser::new(&source).parse() {
+                Err(ref e) if e.is_incomplete() => continue,
+                result => break result,
+            }
+        };
 
-        match Parser::new(&line).parse() {
+        if let Some(ref mut editor) = editor {
+            if let Err(e) = editor.history.add(source.trim_end_matches('\n')) {
+                writeln!(&mut io::stderr(), "shroom: can't save history, so it won't be kept: {}",
+                         e).unwrap();
+            }
+        }
+
+        match parsed {
             Ok(ast) => {
-                let exit_code = execute(&ast);
-                if exit_code != 0 {
-                    println!("shroom: exit code: {}", exit_code);
+                // Only a command interrupted on this line should stop the rest of it.
+                shell.last_termination = Termination::Exited(0);
+                exit_code = execute(shell, &ast);
+                if exit_code != 0 && interactive {
+                    // The terminal echoes `^C` or `^\` without a newline, so end that line first.
+                    if let Termination::Signaled { signal: libc::SIGINT | libc::SIGQUIT, .. } =
+                            shell.last_termination {
+                        println!();
+                    }
+                    if let Some(message) = shell.status_message(exit_code) {
+                        println!("{}", message);
+                    }
                 }
             },
 
+            Err(parse_error) if interactive => {
+                exit_code = 2;
+                println!("shroom: {}", parse_error.render(&source, None, 1));
+            },
+
             Err(parse_error) => {
-                println!("shroom: parse error: {}", parse_error);
+                exit_code = 2;
+                let rendered = parse_error.render(&source, Some("<stdin>"), line_number);
+                writeln!(&mut io::stderr(), "shroom: {}", rendered).unwrap();
             },
         }
+        line_number += source.matches('\n').count();
+    }
+}
 
-        line.clear();
+fn usage() -> ! {
+    writeln!(&mut io::stderr(), "usage: shroom [--norc] [-c command | script] [args...]")
+        .unwrap();
+    std::process::exit(2);
+}
+
+fn main() {
+    let mut shell = Shell::new();
+    let mut args = std::env::args().skip(1).peekable();
+
+    // `--norc` skips the config files an interactive shell would run.
+    let norc = args.peek().is_some_and(|arg| arg == "--norc");
+    if norc {
+        args.next();
     }
+
+    let exit_code = match args.next() {
+        None => {
+            shell.interactive = io::stdin().is_terminal();
+            if shell.interactive {
+                if let Err(e) = shell.jobs.enable_job_control() {
+                    writeln!(&mut io::stderr(), "shroom: can't enable job control: {}", e).unwrap();
+                    signals::init(true);
+                }
+                shell.visited_dirs = load_visited_dirs();
+                if !norc {
+                    run_config(&mut shell);
+                }
+            } else {
+                signals::init(false);
+            }
+            run_interactive(&mut shell)
+        },
+
+        Some(ref flag) if flag == "-c" => {
+            signals::init(false);
+            let command = args.next().unwrap_or_else(|| usage());
+            shell.variables.set("argv", args.collect());
+            run_source(&mut shell, "-c", &command)
+        },
+
+        Some(ref flag) if flag.starts_with('-') => usage(),
+
+        Some(path) => {
+            signals::init(false);
+            shell.variables.set("argv", args.collect());
+            match std::fs::read_to_string(&path) {
+                Ok(source) => run_source(&mut shell, &path, &source),
+                Err(e) => {
+                    writeln!(&mut io::stderr(), "shroom: {}: {}", path, e).unwrap();
+                    127
+                },
+            }
+        },
+    };
+
+    std::process::exit(exit_code);
 }
@@ -1,63 +1,444 @@
+use std::ops::Range;
 use std::{error, fmt};
-use std::io::Write;
+use variables;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Expr {
     Text(String),
+
+    /// `$name` or `${name}`, which expands to every value in the variable's list.
+    Variable(String),
+
+    /// An unquoted wildcard, `*`, `**`, `?` or `[...]`, which makes the word it's in a pattern
+    /// matched against file paths.
+    Glob(String),
+
+    /// `~` or `~name` at the start of a word, holding the name, which expands to a home
+    /// directory, or the current or previous directory for `~+` and `~-`.
+    Tilde(String),
+
+    /// `{a,b,c}`, which expands to each of the alternatives in turn.
+    Brace(Vec<Vec<Expr>>),
+
+    /// `$(...)`, which runs the commands inside and expands to their output as a single value,
+    /// without any trailing newlines.
+    CommandSubstitution(Box<Ast>),
+
+    /// `$@(...)`, which runs the commands inside and expands to each line of their output as a
+    /// separate value.
+    LineSubstitution(Box<Ast>),
+
+    /// A double-quoted string with variables or command substitutions in it, which always
+    /// expands to a single value. The parts are only `Text`, `Variable` and
+    /// `CommandSubstitution`, and a variable's values are joined with spaces.
+    Quoted(Vec<Expr>),
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Ast {
     Empty,
-    Call { command: String, args: Vec<Vec<Expr>> }
+    Call { command: Vec<Expr>, args: Vec<Vec<Expr>>, redirections: Vec<Redirection> },
+
+    /// Two or more commands with each one's stdout connected to the next one's stdin.
+    Pipeline(Vec<Ast>),
+
+    /// Commands separated by `;` or newlines, run one after another.
+    Sequence(Vec<Ast>),
+
+    /// `left && right`, which runs `right` only if `left` succeeded.
+    And(Box<Ast>, Box<Ast>),
+
+    /// `left || right`, which runs `right` only if `left` failed.
+    Or(Box<Ast>, Box<Ast>),
+
+    /// A call or pipeline followed by `&`, which runs as a background job.
+    Background(Box<Ast>),
+
+    /// `function name; ...; end`, which defines a function run like a command.
+    Function { name: String, body: Box<Ast> },
+
+    /// `if condition; ...; else ...; end`, where `else if` is an `If` in `otherwise`.
+    If { condition: Box<Ast>, then: Box<Ast>, otherwise: Option<Box<Ast>> },
+
+    /// `while condition; ...; end`
+    While { condition: Box<Ast>, body: Box<Ast> },
+
+    /// `for variable in items...; ...; end`
+    For { variable: String, items: Vec<Vec<Expr>>, body: Box<Ast> },
+}
+
+/// A redirection of one of a command's file descriptors, applied in the order written.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Redirection {
+    pub fd: u32,
+    pub target: RedirectTarget,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RedirectTarget {
+    /// `< file`
+    Input(Vec<Expr>),
+
+    /// `> file`
+    Output(Vec<Expr>),
+
+    /// `>> file`
+    Append(Vec<Expr>),
+
+    /// `>&fd`, making `fd` a copy of another of the command's file descriptors.
+    Duplicate(u32),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RedirectOp {
+    Input,
+    Output,
+    Append,
+    Duplicate(u32),
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Token {
     Newline,
+    Semicolon,
+    And,
+    Or,
+    Ampersand,
+    Pipe,
+    /// A redirection operator with the file descriptor it applies to, like `2>`.
+    Redirect(u32, RedirectOp),
     Whitespace,
     Text(String),
+    Variable(String),
+    Glob(String),
+    Tilde(String),
+    BraceOpen,
+    /// A `,` between the alternatives in braces.
+    Comma,
+    BraceClose,
+    CommandSubstitution(Box<Ast>),
+    LineSubstitution(Box<Ast>),
+    /// The `)` ending a command substitution.
+    CloseParen,
+    /// A double-quoted string which needs interpolating, holding its parts.
+    Quoted(Vec<Expr>),
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Lexer<'src> {
     source: &'src str,
     position: usize,
+
+    /// Where the token most recently returned by `next` starts.
+    token_start: usize,
+
+    /// How many `{` are open, since `,` and `}` only separate alternatives inside braces.
+    brace_depth: usize,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub enum ParseError {
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ParseErrorKind {
     UnclosedDelimiter,
     UnexpectedChar,
     UnexpectedEnd,
+
+    /// A range in braces, like `{1..10}`, with too many values to expand.
+    RangeTooLong,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+
+    /// The byte range of the source the error is about. For `UnclosedDelimiter` it's the opening
+    /// delimiter, and for `UnexpectedEnd` it's empty, at the end of the source.
+    pub span: Range<usize>,
+
+    /// The text of the span, or just its first character for `UnclosedDelimiter`, or `None` at
+    /// the end of the source.
+    pub found: Option<String>,
+
+    /// A description of what should have been there instead, like "a command".
+    pub expected: &'static str,
 }
 
 pub type ParseResult<T> = Result<T, ParseError>;
 
-impl error::Error for ParseError {
-    fn description(&self) -> &str {
-        match *self {
-            ParseError::UnclosedDelimiter => "unclosed delimiter",
-            ParseError::UnexpectedChar    => "unexpected character",
-            ParseError::UnexpectedEnd     => "unexpected end of input",
+impl ParseError {
+    fn new(source: &str, kind: ParseErrorKind, span: Range<usize>, expected: &'static str)
+           -> ParseError {
+        let found = match kind {
+            ParseErrorKind::UnexpectedChar if span.end > span.start => {
+                Some(String::from(&source[span.clone()]))
+            },
+            _ => source[span.start..].chars().next().map(String::from),
+        };
+        ParseError { kind, span, found, expected }
+    }
+
+    /// An error about the character at `position`, or about reaching the end of the source if
+    /// it's at the end.
+    fn unexpected(source: &str, position: usize, expected: &'static str) -> ParseError {
+        match source[position..].chars().next() {
+            Some(c) => {
+                let span = position..position + c.len_utf8();
+                ParseError::new(source, ParseErrorKind::UnexpectedChar, span, expected)
+            },
+            None => {
+                ParseError::new(source, ParseErrorKind::UnexpectedEnd, position..position, expected)
+            },
+        }
+    }
+
+    /// Whether the error is only because the source stops too soon, like an unclosed quote or a
+    /// trailing `|`, so that more input could complete it.
+    pub fn is_incomplete(&self) -> bool {
+        match self.kind {
+            ParseErrorKind::UnclosedDelimiter | ParseErrorKind::UnexpectedEnd => true,
+            ParseErrorKind::UnexpectedChar | ParseErrorKind::RangeTooLong => false,
+        }
+    }
+
+    /// Render the error like rustc does, showing the line it's on with the span underlined. The
+    /// location is given as `name:line:column` when there's a file name. Lines are numbered from
+    /// `first_line`, for source which is read and parsed a command at a time.
+    pub fn render(&self, source: &str, name: Option<&str>, first_line: usize) -> String {
+        // Errors at the end of the source are shown after the last character rather than on the
+        // empty line after a final newline.
+        let end_of_text = source.trim_end_matches('\n').len();
+        let start = self.span.start.min(end_of_text);
+
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+        let line = &source[line_start..line_end];
+        let line_number = source[..start].matches('\n').count() + first_line;
+        let column = source[line_start..start].chars().count() + 1;
+
+        // Keep tabs in the padding so the caret lines up however wide they're shown.
+        let padding: String = source[line_start..start].chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let end = self.span.end.clamp(start, line_end);
+        let underline = "^".repeat(source[start..end].chars().count().max(1));
+
+        let gutter = " ".repeat(line_number.to_string().len());
+        let mut rendered = format!("parse error: {}\n", self);
+        if let Some(name) = name {
+            rendered.push_str(&format!("{}--> {}:{}:{}\n", gutter, name, line_number, column));
         }
+        rendered.push_str(&format!("{} |\n", gutter));
+        rendered.push_str(&format!("{} | {}\n", line_number, line));
+        rendered.push_str(&format!("{} | {}{}", gutter, padding, underline));
+        rendered
     }
 }
 
+impl error::Error for ParseError {}
+
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", error::Error::description(self))
+        match (self.kind, &self.found) {
+            (ParseErrorKind::UnclosedDelimiter, Some(c)) => write!(f, "unclosed `{}`", c)?,
+            (ParseErrorKind::UnexpectedChar, Some(text)) => {
+                write!(f, "unexpected `{}`", text.escape_debug())?
+            },
+            (ParseErrorKind::RangeTooLong, _) => write!(f, "range in braces is too long")?,
+            _ => write!(f, "unexpected end of input")?,
+        }
+        write!(f, ", expected {}", self.expected)
+    }
+}
+
+/// Write text so that it lexes back to the same text, single-quoting it if needed.
+fn fmt_text(f: &mut fmt::Formatter, text: &str) -> fmt::Result {
+    if !text.is_empty() && text.chars().all(Lexer::is_unquoted_text) {
+        write!(f, "{}", text)
+    } else {
+        write!(f, "'{}'", text.replace('\'', "'\\''"))
+    }
+}
+
+/// Write a variable reference, in braces if the `next` part would otherwise be read as part of
+/// the name.
+fn fmt_variable(f: &mut fmt::Formatter, name: &str, next: Option<&Expr>) -> fmt::Result {
+    let needs_braces = match next {
+        Some(Expr::Text(next)) => next.starts_with(variables::is_name_char),
+        _ => false,
+    };
+    if needs_braces {
+        write!(f, "${{{}}}", name)
+    } else {
+        write!(f, "${}", name)
+    }
+}
+
+fn fmt_word(f: &mut fmt::Formatter, word: &[Expr]) -> fmt::Result {
+    for (i, expr) in word.iter().enumerate() {
+        match *expr {
+            Expr::Text(ref text) => fmt_text(f, text)?,
+            Expr::Variable(ref name) => fmt_variable(f, name, word.get(i + 1))?,
+            Expr::Glob(ref pattern) => write!(f, "{}", pattern)?,
+            Expr::Tilde(ref name) => write!(f, "~{}", name)?,
+            Expr::Brace(ref alternatives) => {
+                write!(f, "{{")?;
+                for (i, alternative) in alternatives.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    fmt_word(f, alternative)?;
+                }
+                write!(f, "}}")?;
+            },
+            Expr::CommandSubstitution(ref ast) => write!(f, "$({})", ast)?,
+            Expr::LineSubstitution(ref ast) => write!(f, "$@({})", ast)?,
+            Expr::Quoted(ref parts) => {
+                write!(f, "\"")?;
+                for (i, part) in parts.iter().enumerate() {
+                    match *part {
+                        Expr::Text(ref text) => {
+                            for c in text.chars() {
+                                if matches!(c, '\\' | '"' | '$') {
+                                    write!(f, "\\")?;
+                                }
+                                write!(f, "{}", c)?;
+                            }
+                        },
+                        Expr::Variable(ref name) => fmt_variable(f, name, parts.get(i + 1))?,
+                        _ => fmt_word(f, std::slice::from_ref(part))?,
+                    }
+                }
+                write!(f, "\"")?;
+            },
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Redirection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (default_fd, op) = match self.target {
+            RedirectTarget::Input(_) => (0, "<"),
+            RedirectTarget::Output(_) => (1, ">"),
+            RedirectTarget::Append(_) => (1, ">>"),
+            RedirectTarget::Duplicate(_) => (1, ">&"),
+        };
+
+        if self.fd != default_fd {
+            write!(f, "{}", self.fd)?;
+        }
+        write!(f, "{}", op)?;
+
+        match self.target {
+            RedirectTarget::Input(ref word) |
+            RedirectTarget::Output(ref word) |
+            RedirectTarget::Append(ref word) => {
+                write!(f, " ")?;
+                fmt_word(f, word)
+            },
+            RedirectTarget::Duplicate(fd) => write!(f, "{}", fd),
+        }
+    }
+}
+
+/// Displays the AST as source code which parses back to it, for showing commands to the user.
+impl fmt::Display for Ast {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn join(f: &mut fmt::Formatter, asts: &[Ast], separator: &str) -> fmt::Result {
+            for (i, ast) in asts.iter().enumerate() {
+                if i > 0 {
+                    write!(f, "{}", separator)?;
+                }
+                write!(f, "{}", ast)?;
+            }
+            Ok(())
+        }
+
+        match *self {
+            Ast::Empty => Ok(()),
+
+            Ast::Call { ref command, ref args, ref redirections } => {
+                fmt_word(f, command)?;
+                for arg in args {
+                    write!(f, " ")?;
+                    fmt_word(f, arg)?;
+                }
+                for redirection in redirections {
+                    write!(f, " {}", redirection)?;
+                }
+                Ok(())
+            },
+
+            Ast::Pipeline(ref calls) => join(f, calls, " | "),
+            Ast::Sequence(ref statements) => join(f, statements, "; "),
+            Ast::And(ref left, ref right) => write!(f, "{} && {}", left, right),
+            Ast::Or(ref left, ref right) => write!(f, "{} || {}", left, right),
+            Ast::Background(ref ast) => write!(f, "{} &", ast),
+
+            Ast::Function { ref name, ref body } => {
+                write!(f, "function ")?;
+                fmt_text(f, name)?;
+                write!(f, "; {}; end", body)
+            },
+
+            Ast::If { ref condition, ref then, ref otherwise } => {
+                write!(f, "if {}; {}", condition, then)?;
+                match *otherwise {
+                    // `else if` shares the `end` of the `if` it's part of.
+                    Some(ref otherwise) if matches!(**otherwise, Ast::If { .. }) => {
+                        let otherwise = otherwise.to_string();
+                        write!(f, "; else {}", otherwise.strip_suffix("; end").unwrap())?;
+                    },
+                    Some(ref otherwise) => write!(f, "; else; {}", otherwise)?,
+                    None => {},
+                }
+                write!(f, "; end")
+            },
+
+            Ast::While { ref condition, ref body } => {
+                write!(f, "while {}; {}; end", condition, body)
+            },
+
+            Ast::For { ref variable, ref items, ref body } => {
+                write!(f, "for {} in", variable)?;
+                for item in items {
+                    write!(f, " ")?;
+                    fmt_word(f, item)?;
+                }
+                write!(f, "; {}; end", body)
+            },
+        }
     }
 }
 
 impl<'src> Lexer<'src> {
     pub fn new(source: &'src str) -> Lexer<'src> {
         Lexer {
-            source: source,
+            source,
             position: 0,
+            token_start: 0,
+            brace_depth: 0,
         }
     }
 
+    pub fn token_start(&self) -> usize {
+        self.token_start
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    fn unexpected(&self, position: usize, expected: &'static str) -> ParseError {
+        ParseError::unexpected(self.source, position, expected)
+    }
+
+    /// An error for a delimiter at `position` which is never closed.
+    fn unclosed(&self, position: usize, expected: &'static str) -> ParseError {
+        let c = self.source[position..].chars().next().unwrap();
+        let span = position..position + c.len_utf8();
+        ParseError::new(self.source, ParseErrorKind::UnclosedDelimiter, span, expected)
+    }
+
     fn read_char(&mut self) -> Option<char> {
         let opt_c = self.source[self.position..].chars().next();
 
@@ -80,11 +461,18 @@ impl<'src> Lexer<'src> {
         c == ' ' || c == '\t'
     }
 
-    fn is_unquoted_text(c: char) -> bool {
-        match c {
-            'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '+' | '/' | '_' | '.' => true,
-            _ => false,
-        }
+    pub fn is_unquoted_text(c: char) -> bool {
+        matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '+' | '/' | '_' | '.' | '=' | ':' |
+                    ',' | '@' | '%')
+    }
+
+    /// Whether a word would start at `position`, rather than it continuing a word started by
+    /// the text or quotes just before it.
+    fn is_word_start(&self, position: usize) -> bool {
+        self.source[..position].chars().next_back().is_none_or(|c| {
+            !Lexer::is_unquoted_text(c) &&
+                !matches!(c, '"' | '\'' | '\\' | '#' | '*' | '?' | '[' | ']' | '{' | '}')
+        })
     }
 
     fn skip_while<F>(&mut self, mut predicate: F) where F: FnMut(char) -> bool {
@@ -103,32 +491,127 @@ impl<'src> Lexer<'src> {
 
     fn lex_unquoted_text(&mut self) -> ParseResult<Token> {
         let start = self.position;
-        self.skip_while(Lexer::is_unquoted_text);
+        let in_braces = self.brace_depth > 0;
+        self.skip_while(|c| Lexer::is_unquoted_text(c) && !(in_braces && c == ','));
         let end = self.position;
 
-        let text = String::from(&self.source[start..end]);
-        Ok(Token::Text(text))
+        let text = &self.source[start..end];
+
+        // A number at the start of a word immediately followed by `<` or `>` is the file
+        // descriptor a redirection applies to, as in `2>`.
+        if self.is_word_start(start) && text.bytes().all(|b| b.is_ascii_digit()) {
+            if let Some(op) = self.read_char() {
+                if op == '<' || op == '>' {
+                    if let Ok(fd) = text.parse() {
+                        return self.lex_redirect(fd, op);
+                    }
+                }
+                self.unread_char();
+            }
+        }
+
+        Ok(Token::Text(String::from(text)))
+    }
+
+    /// Lex an operator which means something different when its character is doubled, like `|`
+    /// and `||`.
+    fn lex_double(&mut self, c: char, double: Token, single: Token) -> Token {
+        match self.read_char() {
+            Some(next) if next == c => double,
+            Some(_) => {
+                self.unread_char();
+                single
+            },
+            None => single,
+        }
     }
 
+    /// Lex the rest of a redirection operator after its `<` or `>`.
+    fn lex_redirect(&mut self, fd: u32, op: char) -> ParseResult<Token> {
+        if op == '<' {
+            return Ok(Token::Redirect(fd, RedirectOp::Input));
+        }
+
+        match self.read_char() {
+            Some('>') => Ok(Token::Redirect(fd, RedirectOp::Append)),
+            Some('&') => {
+                let start = self.position;
+                self.skip_while(|c| c.is_ascii_digit());
+                let target = self.source[start..self.position].parse()
+                    .map_err(|_| self.unexpected(start, "a file descriptor number"))?;
+                Ok(Token::Redirect(fd, RedirectOp::Duplicate(target)))
+            },
+            Some(_) => {
+                self.unread_char();
+                Ok(Token::Redirect(fd, RedirectOp::Output))
+            },
+            None => Ok(Token::Redirect(fd, RedirectOp::Output)),
+        }
+    }
+
+    /// Lex a double-quoted string after its `"`. Variables and command substitutions in it are
+    /// interpolated, while a `$` not followed by a name or `(` is just text. A string with
+    /// nothing to interpolate is plain text.
     fn lex_double_quoted_text(&mut self) -> ParseResult<Token> {
+        let start = self.position - 1;
+        let mut parts = vec![];
         let mut text = String::new();
 
         while let Some(c) = self.read_char() {
             match c {
-                '"'  => return Ok(Token::Text(text)),
-                '\\' => try!(self.lex_double_quote_escape(&mut text)),
+                '"'  => {
+                    if parts.is_empty() {
+                        return Ok(Token::Text(text));
+                    }
+                    if !text.is_empty() {
+                        parts.push(Expr::Text(text));
+                    }
+                    return Ok(Token::Quoted(parts));
+                },
+                '\\' => self.lex_double_quote_escape(&mut text)?,
+                '$' => {
+                    let part = match self.source[self.position..].chars().next() {
+                        Some('(') => {
+                            self.read_char();
+                            Expr::CommandSubstitution(self.lex_command_substitution()?)
+                        },
+                        Some(c) if c == '{' || variables::is_name_char(c) => {
+                            match self.lex_variable()? {
+                                Token::Variable(name) => Expr::Variable(name),
+                                _ => unreachable!(),
+                            }
+                        },
+                        _ => {
+                            text.push('$');
+                            continue;
+                        },
+                    };
+
+                    if !text.is_empty() {
+                        parts.push(Expr::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(part);
+                },
                 c => text.push(c),
             };
         }
 
-        Err(ParseError::UnclosedDelimiter)
+        Err(self.unclosed(start, "a closing `\"`"))
     }
 
     fn lex_double_quote_escape(&mut self, text: &mut String) -> ParseResult<()> {
-        let escaped = try!(self.read_char().ok_or(ParseError::UnexpectedEnd));
+        let escaped = self.read_char()
+            .ok_or_else(|| self.unexpected(self.position, "a character after `\\`"))?;
 
         match escaped {
-            '\\' | '"' => text.push(escaped),
+            '\\' | '"' | '$' => text.push(escaped),
+            'n' => text.push('\n'),
+            't' => text.push('\t'),
+            'r' => text.push('\r'),
+            'e' => text.push('\x1b'),
+            'u' => text.push(self.lex_unicode_escape()?),
+            // A backslash before a newline continues the line without inserting anything.
+            '\n' => {},
             c => {
                 text.push('\\');
                 text.push(c);
@@ -137,77 +620,928 @@ impl<'src> Lexer<'src> {
 
         Ok(())
     }
+
+    /// Lex the `{...}` part of a `\u{...}` escape, containing the hex code of a Unicode scalar
+    /// value.
+    fn lex_unicode_escape(&mut self) -> ParseResult<char> {
+        let open = self.position;
+        if self.read_char() != Some('{') {
+            return Err(self.unexpected(open, "`{` after `\\u`"));
+        }
+
+        let start = self.position;
+        self.skip_while(|c| c.is_ascii_hexdigit());
+        let end = self.position;
+
+        match self.read_char() {
+            Some('}') => {},
+            Some(_) => return Err(self.unexpected(end, "a hex digit or `}`")),
+            None => return Err(self.unclosed(open, "a closing `}`")),
+        }
+
+        u32::from_str_radix(&self.source[start..end], 16).ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| {
+                ParseError::new(self.source, ParseErrorKind::UnexpectedChar, open..end + 1,
+                                "the hex code of a Unicode character")
+            })
+    }
+
+    /// Parse the commands in a command substitution after its `(`, up to the matching `)`.
+    fn lex_command_substitution(&mut self) -> ParseResult<Box<Ast>> {
+        let open = self.position - 1;
+        let mut parser = Parser {
+            lexer: Lexer { position: self.position, ..Lexer::new(self.source) },
+            peeked: None,
+            substitution_start: Some(open),
+        };
+
+        let ast = parser.parse_sequence()?;
+        parser.next_token()?;
+        self.position = parser.lexer.position;
+        Ok(Box::new(ast))
+    }
+
+    /// Lex a variable reference or command substitution after its `$`. A variable is either a
+    /// bare name or a name in braces.
+    fn lex_variable(&mut self) -> ParseResult<Token> {
+        let open = self.position;
+        let braced = match self.read_char() {
+            Some('(') => return Ok(Token::CommandSubstitution(self.lex_command_substitution()?)),
+            Some('@') if self.source[self.position..].starts_with('(') => {
+                self.read_char();
+                return Ok(Token::LineSubstitution(self.lex_command_substitution()?));
+            },
+            Some('{') => true,
+            Some(_) => {
+                self.unread_char();
+                false
+            },
+            None => return Err(self.unexpected(open, "a variable name after `$`")),
+        };
+
+        let start = self.position;
+        self.skip_while(variables::is_name_char);
+        let end = self.position;
+
+        if start == end {
+            return Err(self.unexpected(start, "a variable name after `$`"));
+        }
+
+        if braced {
+            match self.read_char() {
+                Some('}') => {},
+                Some(_) => return Err(self.unexpected(end, "`}` after the variable name")),
+                None => return Err(self.unclosed(open, "a closing `}`")),
+            }
+        }
+
+        Ok(Token::Variable(String::from(&self.source[start..end])))
+    }
+
+    /// Lex the rest of a `[...]` wildcard after its `[`. If there's no `]` before the end of the
+    /// word, the `[` is just text, so commands like `[ -f file ]` still work.
+    fn lex_bracket(&mut self) -> Token {
+        let start = self.position - 1;
+        let mut first = true;
+        while let Some(c) = self.read_char() {
+            match c {
+                // A `]` straight after the `[` is part of the class rather than closing it.
+                ']' if !first => {
+                    return Token::Glob(String::from(&self.source[start..self.position]));
+                },
+                '\\' => {
+                    self.read_char();
+                },
+                c if Lexer::is_whitespace(c) || matches!(c, '\n' | ';' | '|' | '&' | '<' | '>' |
+                                                          '"' | '\'' | '$') => break,
+                _ => {},
+            }
+            first = false;
+        }
+
+        self.position = start + 1;
+        Token::Text(String::from("["))
+    }
+
+    /// Lex the rest of a `~` or `~name` after its `~`. It's only special at the start of a word
+    /// and when followed by a `/` or the end of the word.
+    fn lex_tilde(&mut self) -> Token {
+        let tilde = self.position - 1;
+        if !self.is_word_start(tilde) {
+            return Token::Text(String::from("~"));
+        }
+
+        let start = self.position;
+        self.skip_while(|c| Lexer::is_unquoted_text(c) && c != '/' && c != ',');
+        let end = self.position;
+
+        let ends_word = match self.source[end..].chars().next() {
+            None => true,
+            Some(c) => {
+                Lexer::is_whitespace(c) ||
+                    matches!(c, '/' | '\r' | '\n' | ';' | '|' | '&' | '<' | '>') ||
+                    (self.brace_depth > 0 && (c == ',' || c == '}'))
+            },
+        };
+        if !ends_word {
+            self.position = start;
+            return Token::Text(String::from("~"));
+        }
+
+        Token::Tilde(String::from(&self.source[start..end]))
+    }
+
+    /// Single-quoted text is taken literally, with no escapes.
+    fn lex_single_quoted_text(&mut self) -> ParseResult<Token> {
+        let start = self.position;
+        self.skip_while(|c| c != '\'');
+        let end = self.position;
+
+        match self.read_char() {
+            Some(_) => Ok(Token::Text(String::from(&self.source[start..end]))),
+            None => Err(self.unclosed(start - 1, "a closing `'`")),
+        }
+    }
 }
 
 impl<'src> Iterator for Lexer<'src> {
     type Item = ParseResult<Token>;
 
     fn next(&mut self) -> Option<ParseResult<Token>> {
-        self.read_char().map(|c| {
-            match c {
-                c if Lexer::is_whitespace(c)    => self.lex_whitespace(),
-                c if Lexer::is_unquoted_text(c) => {
-                    self.unread_char();
-                    self.lex_unquoted_text()
-                },
-                '\r' | '\n'                     => Ok(Token::Newline),
-                '"'                             => self.lex_double_quoted_text(),
-                _                               => Err(ParseError::UnexpectedChar),
-            }
+        self.token_start = self.position;
+
+        let c = self.read_char()?;
+        Some(match c {
+            ',' if self.brace_depth > 0     => Ok(Token::Comma),
+            c if Lexer::is_whitespace(c)    => self.lex_whitespace(),
+            c if Lexer::is_unquoted_text(c) => {
+                self.unread_char();
+                self.lex_unquoted_text()
+            },
+            '\r' | '\n'                     => Ok(Token::Newline),
+            ';'                             => Ok(Token::Semicolon),
+            '|'                             => Ok(self.lex_double('|', Token::Or, Token::Pipe)),
+            '&'                             => {
+                Ok(self.lex_double('&', Token::And, Token::Ampersand))
+            },
+            '<'                             => self.lex_redirect(0, c),
+            '>'                             => self.lex_redirect(1, c),
+            '"'                             => self.lex_double_quoted_text(),
+            ')'                             => Ok(Token::CloseParen),
+            '\''                            => self.lex_single_quoted_text(),
+            '$'                             => self.lex_variable(),
+            '*'                             => {
+                let start = self.position - 1;
+                self.skip_while(|c| c == '*');
+                Ok(Token::Glob(String::from(&self.source[start..self.position])))
+            },
+            '?'                             => Ok(Token::Glob(String::from("?"))),
+            '['                             => Ok(self.lex_bracket()),
+            ']'                             => Ok(Token::Text(String::from("]"))),
+            '~'                             => Ok(self.lex_tilde()),
+            '{'                             => {
+                self.brace_depth += 1;
+                Ok(Token::BraceOpen)
+            },
+            '}' if self.brace_depth > 0     => {
+                self.brace_depth -= 1;
+                Ok(Token::BraceClose)
+            },
+            '}'                             => Ok(Token::Text(String::from("}"))),
+            '#'                             => {
+                // A `#` starting a word begins a comment running to the end of the line, which
+                // also covers `#!` lines at the start of scripts.
+                if self.is_word_start(self.position - 1) {
+                    self.skip_while(|c| c != '\n');
+                    return self.next();
+                }
+                Ok(Token::Text(String::from("#")))
+            },
+            '\\'                            => {
+                match self.read_char() {
+                    // A backslash before a newline continues the line, so the lexer carries
+                    // on with whatever comes after it.
+                    Some('\n') => {
+                        let end = self.position;
+                        return self.next().or_else(|| {
+                            Some(Err(self.unexpected(end, "another line after `\\`")))
+                        });
+                    },
+                    Some(escaped) => Ok(Token::Text(escaped.to_string())),
+                    None => Err(self.unexpected(self.position, "a character after `\\`")),
+                }
+            },
+            _                               => {
+                Err(self.unexpected(self.position - c.len_utf8(), "a word or an operator"))
+            },
         })
     }
 }
 
+/// Words which start or end blocks when they're the first word of a statement.
+const KEYWORDS: [&str; 7] = ["if", "else", "end", "while", "for", "in", "function"];
+
 #[derive(Clone)]
 pub struct Parser<'src> {
     lexer: Lexer<'src>,
+
+    /// The next token and where it is in the source, if it has been lexed already.
+    peeked: Option<(Token, Range<usize>)>,
+
+    /// Where the `(` of the `$(` is, when parsing the inside of a command substitution.
+    substitution_start: Option<usize>,
 }
 
 impl<'src> Parser<'src> {
     pub fn new(input: &'src str) -> Parser<'src> {
-        Parser { lexer: Lexer::new(input) }
+        Parser { lexer: Lexer::new(input), peeked: None, substitution_start: None }
     }
 
     pub fn parse(&mut self) -> ParseResult<Ast> {
-        if let Some(token_result) = self.lexer.next() {
-            let token = try!(token_result);
-            match token {
-                Token::Whitespace | Token::Newline => self.parse(),
-                Token::Text(command) => self.parse_call(command),
-            }
-        } else {
-            Ok(Ast::Empty)
+        self.parse_sequence()
+    }
+
+    fn lex_token(&mut self) -> ParseResult<Option<(Token, Range<usize>)>> {
+        match self.lexer.next() {
+            Some(token) => Ok(Some((token?, self.lexer.token_start()..self.lexer.position()))),
+            None => Ok(None),
         }
     }
 
-    fn parse_call(&mut self, command: String) -> ParseResult<Ast> {
-        let mut args = vec![];
-        let mut current_arg = vec![];
+    fn next_token(&mut self) -> ParseResult<Option<Token>> {
+        match self.peeked.take() {
+            Some((token, _)) => Ok(Some(token)),
+            None => Ok(self.lex_token()?.map(|(token, _)| token)),
+        }
+    }
+
+    fn peek_token(&mut self) -> ParseResult<Option<&Token>> {
+        if self.peeked.is_none() {
+            self.peeked = self.lex_token()?;
+        }
+        Ok(self.peeked.as_ref().map(|(token, _)| token))
+    }
+
+    /// An error about the next token, or about reaching the end of input if there isn't one.
+    fn unexpected_token(&mut self, expected: &'static str) -> ParseError {
+        let span = match self.peek_token() {
+            Ok(_) => self.peeked.as_ref().map(|(_, span)| span.clone()),
+            Err(e) => return e,
+        };
+        let source = self.lexer.source;
+        match span {
+            Some(span) => ParseError::new(source, ParseErrorKind::UnexpectedChar, span, expected),
+            None => ParseError::unexpected(source, source.len(), expected),
+        }
+    }
+
+    /// Skip whitespace and newlines.
+    fn skip_blank(&mut self) -> ParseResult<()> {
+        while let Some(&Token::Whitespace) | Some(&Token::Newline) = self.peek_token()? {
+            self.next_token()?;
+        }
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) -> ParseResult<()> {
+        while let Some(&Token::Whitespace) = self.peek_token()? {
+            self.next_token()?;
+        }
+        Ok(())
+    }
+
+    /// Parse statements separated by `;` or newlines until the end of input, or the `)` ending a
+    /// command substitution, which is left to be read.
+    fn parse_sequence(&mut self) -> ParseResult<Ast> {
+        self.parse_block(&[]).map(|(ast, _)| ast)
+    }
+
+    /// If the next token is a keyword like `if` or `end` standing alone as a word, return it.
+    fn peek_keyword(&mut self) -> ParseResult<Option<&'static str>> {
+        let keyword = match self.peek_token()? {
+            Some(Token::Text(text)) => KEYWORDS.iter().find(|&&keyword| keyword == text),
+            _ => None,
+        };
+        let keyword = match keyword {
+            Some(&keyword) => keyword,
+            None => return Ok(None),
+        };
+
+        // Something like `end"s"` is a word which starts with a keyword, not the keyword itself.
+        match self.lexer.clone().next().transpose()? {
+            None | Some(Token::Whitespace) | Some(Token::Newline) | Some(Token::Semicolon) |
+            Some(Token::CloseParen) => Ok(Some(keyword)),
+            Some(_) => Ok(None),
+        }
+    }
+
+    /// Parse statements like `parse_sequence` until one of the `terminators` keywords, like `end`,
+    /// which is left to be read and returned. With no terminators, the block goes on until the
+    /// end of input.
+    fn parse_block(&mut self, terminators: &[&'static str])
+                   -> ParseResult<(Ast, Option<&'static str>)> {
+        let in_substitution = self.substitution_start.is_some();
+        let mut statements = vec![];
+        let mut terminator = None;
 
-        for token_result in &mut self.lexer {
-            let token = try!(token_result);
-            match token {
-                Token::Newline => {
-                    if !current_arg.is_empty() {
-                        args.push(current_arg);
+        loop {
+            self.skip_blank()?;
+            match self.peek_token()? {
+                None => {
+                    if !terminators.is_empty() {
+                        return Err(self.unexpected_token("`end`"));
                     }
+                    if let Some(open) = self.substitution_start {
+                        let source = self.lexer.source;
+                        return Err(ParseError::new(source, ParseErrorKind::UnclosedDelimiter,
+                                                   open..open + 1, "a closing `)`"));
+                    }
+                    break;
+                },
+                Some(&Token::CloseParen) if in_substitution && terminators.is_empty() => break,
+                Some(&Token::Semicolon) => {
+                    self.next_token()?;
+                    continue;
+                },
+                Some(_) => {},
+            }
+
+            match self.peek_keyword()? {
+                Some(keyword) if terminators.contains(&keyword) => {
+                    terminator = Some(keyword);
                     break;
                 },
+                _ => {},
+            }
+
+            let statement = self.parse_and_or()?;
+
+            match self.peek_token()? {
+                None | Some(&Token::Newline) | Some(&Token::Semicolon) => {
+                    statements.push(statement)
+                },
+
+                Some(&Token::CloseParen) if in_substitution => {
+                    statements.push(statement);
+                    continue;
+                },
+
+                // Only a single pipeline can be put in the background, since the shell can't run
+                // `&&` and `||` chains or blocks apart from itself.
+                Some(&Token::Ampersand) => {
+                    match statement {
+                        Ast::Call { .. } | Ast::Pipeline(_) => {
+                            statements.push(Ast::Background(Box::new(statement)));
+                        },
+                        _ => {
+                            let expected = "`;` or a newline, since only a pipeline can be put \
+                                            in the background";
+                            return Err(self.unexpected_token(expected));
+                        },
+                    }
+                },
+
+                Some(_) => return Err(self.unexpected_token("`;` or a newline")),
+            }
+            self.next_token()?;
+        }
+
+        let ast = match statements.len() {
+            0 => Ast::Empty,
+            1 => statements.pop().unwrap(),
+            _ => Ast::Sequence(statements),
+        };
+        Ok((ast, terminator))
+    }
+
+    /// Skip the `end` keyword closing a block, along with any whitespace after it.
+    fn skip_end(&mut self) -> ParseResult<()> {
+        self.next_token()?;
+        self.skip_whitespace()
+    }
+
+    /// Parse the `if` block starting at the `if` keyword, including any `else` parts.
+    fn parse_if(&mut self) -> ParseResult<Ast> {
+        self.next_token()?;
+        let condition = Box::new(self.parse_and_or()?);
+        let (then, terminator) = self.parse_block(&["else", "end"])?;
+        let then = Box::new(then);
+
+        let otherwise = if terminator == Some("else") {
+            self.next_token()?;
+            self.skip_whitespace()?;
+            if self.peek_keyword()? == Some("if") {
+                // The inner `if` reads the `end` for both.
+                return Ok(Ast::If { condition, then, otherwise: Some(Box::new(self.parse_if()?)) });
+            }
+            let (otherwise, _) = self.parse_block(&["end"])?;
+            Some(Box::new(otherwise))
+        } else {
+            None
+        };
+
+        self.skip_end()?;
+        Ok(Ast::If { condition, then, otherwise })
+    }
+
+    fn parse_function(&mut self) -> ParseResult<Ast> {
+        self.next_token()?;
+        self.skip_whitespace()?;
+        let name = match *self.parse_word()? {
+            [Expr::Text(ref name)] => name.clone(),
+            _ => return Err(self.unexpected_token("a function name")),
+        };
+
+        self.skip_whitespace()?;
+        match self.peek_token()? {
+            None | Some(&Token::Newline) | Some(&Token::Semicolon) => {},
+            Some(_) => {
+                return Err(self.unexpected_token("`;` or a newline after the function name"));
+            },
+        }
+
+        let (body, _) = self.parse_block(&["end"])?;
+        self.skip_end()?;
+        Ok(Ast::Function { name, body: Box::new(body) })
+    }
+
+    fn parse_while(&mut self) -> ParseResult<Ast> {
+        self.next_token()?;
+        let condition = Box::new(self.parse_and_or()?);
+        let (body, _) = self.parse_block(&["end"])?;
+        self.skip_end()?;
+        Ok(Ast::While { condition, body: Box::new(body) })
+    }
+
+    fn parse_for(&mut self) -> ParseResult<Ast> {
+        self.next_token()?;
+        self.skip_whitespace()?;
+        let variable = match self.peek_token()? {
+            Some(Token::Text(name)) if variables::is_valid_name(name) => name.clone(),
+            _ => return Err(self.unexpected_token("a variable name after `for`")),
+        };
+        self.next_token()?;
+
+        self.skip_whitespace()?;
+        if self.peek_keyword()? != Some("in") {
+            return Err(self.unexpected_token("`in` after the variable name"));
+        }
+        self.next_token()?;
+
+        let mut items = vec![];
+        loop {
+            self.skip_whitespace()?;
+            let item = self.parse_word()?;
+            if item.is_empty() {
+                break;
+            }
+            items.push(item);
+        }
+
+        let (body, _) = self.parse_block(&["end"])?;
+        self.skip_end()?;
+        Ok(Ast::For { variable, items, body: Box::new(body) })
+    }
+
+    /// Parse a block like `if ... end`, or else a pipeline.
+    fn parse_command(&mut self) -> ParseResult<Ast> {
+        self.skip_whitespace()?;
+        match self.peek_keyword()? {
+            Some("if") => self.parse_if(),
+            Some("while") => self.parse_while(),
+            Some("for") => self.parse_for(),
+            Some("function") => self.parse_function(),
+            Some("else") | Some("end") => Err(self.unexpected_token("a command")),
+            _ => self.parse_pipeline(),
+        }
+    }
+
+    /// Parse pipelines and blocks joined by `&&` and `||`, which group from left to right.
+    fn parse_and_or(&mut self) -> ParseResult<Ast> {
+        let mut ast = self.parse_command()?;
+
+        loop {
+            let is_and = match self.peek_token()? {
+                Some(&Token::And) => true,
+                Some(&Token::Or) => false,
+                _ => break,
+            };
+            self.next_token()?;
+
+            // The right-hand side may start on the next line.
+            self.skip_blank()?;
+            let right = Box::new(self.parse_command()?);
+            let left = Box::new(ast);
+            ast = if is_and { Ast::And(left, right) } else { Ast::Or(left, right) };
+        }
+
+        Ok(ast)
+    }
+
+    fn parse_pipeline(&mut self) -> ParseResult<Ast> {
+        let mut calls = vec![self.parse_call()?];
+
+        while let Some(&Token::Pipe) = self.peek_token()? {
+            self.next_token()?;
+            self.skip_blank()?;
+            calls.push(self.parse_call()?);
+        }
+
+        if calls.len() == 1 {
+            Ok(calls.pop().unwrap())
+        } else {
+            Ok(Ast::Pipeline(calls))
+        }
+    }
+
+    fn parse_call(&mut self) -> ParseResult<Ast> {
+        self.skip_whitespace()?;
+        let mut words = vec![];
+        let mut redirections = vec![];
+
+        loop {
+            match self.peek_token()? {
+                Some(&Token::Whitespace) => {
+                    self.next_token()?;
+                },
+
+                Some(&Token::Text(_)) | Some(&Token::Variable(_)) | Some(&Token::Glob(_)) |
+                Some(&Token::Tilde(_)) | Some(&Token::BraceOpen) |
+                Some(&Token::CommandSubstitution(_)) | Some(&Token::LineSubstitution(_)) |
+                Some(&Token::Quoted(_)) => {
+                    words.push(self.parse_word()?);
+                },
 
-                Token::Whitespace => {
-                    if !current_arg.is_empty() {
-                        args.push(current_arg);
-                        current_arg = vec![];
+                Some(&Token::Redirect(fd, op)) => {
+                    self.next_token()?;
+                    redirections.push(self.parse_redirection(fd, op)?);
+                },
+
+                Some(&Token::Newline) | Some(&Token::Semicolon) | Some(&Token::And) |
+                Some(&Token::Or) | Some(&Token::Ampersand) | Some(&Token::Pipe) |
+                Some(&Token::CloseParen) | None => break,
+
+                // The lexer only produces these inside braces, which `parse_word` handles.
+                Some(&Token::Comma) | Some(&Token::BraceClose) => unreachable!(),
+            }
+        }
+
+        if words.is_empty() {
+            return Err(self.unexpected_token("a command"));
+        }
+
+        let command = words.remove(0);
+        Ok(Ast::Call { command, args: words, redirections })
+    }
+
+    fn parse_redirection(&mut self, fd: u32, op: RedirectOp) -> ParseResult<Redirection> {
+        let target = match op {
+            RedirectOp::Duplicate(target_fd) => RedirectTarget::Duplicate(target_fd),
+            _ => {
+                self.skip_whitespace()?;
+                let word = self.parse_word()?;
+                if word.is_empty() {
+                    return Err(self.unexpected_token("a file name after the redirection"));
+                }
+
+                match op {
+                    RedirectOp::Input => RedirectTarget::Input(word),
+                    RedirectOp::Output => RedirectTarget::Output(word),
+                    RedirectOp::Append => RedirectTarget::Append(word),
+                    RedirectOp::Duplicate(_) => unreachable!(),
+                }
+            },
+        };
+
+        Ok(Redirection { fd, target })
+    }
+
+    /// Parse the adjacent tokens making up a single word. Returns an empty word if the next token
+    /// can't start one.
+    fn parse_word(&mut self) -> ParseResult<Vec<Expr>> {
+        let mut word = vec![];
+        while self.parse_word_part(&mut word)? {}
+        Ok(word)
+    }
+
+    /// Parse the next part of a word and add it to `word`, returning false if the next token
+    /// isn't part of a word.
+    fn parse_word_part(&mut self, word: &mut Vec<Expr>) -> ParseResult<bool> {
+        match self.peek_token()? {
+            Some(&Token::Text(_)) | Some(&Token::Variable(_)) | Some(&Token::Glob(_)) |
+            Some(&Token::Tilde(_)) | Some(&Token::BraceOpen) |
+            Some(&Token::CommandSubstitution(_)) | Some(&Token::LineSubstitution(_)) |
+            Some(&Token::Quoted(_)) => {},
+            _ => return Ok(false),
+        }
+
+        let open = match self.peeked {
+            Some((Token::BraceOpen, ref span)) => span.start,
+            _ => 0,
+        };
+
+        match self.next_token()? {
+            Some(Token::Text(text)) => push_text(word, text),
+            Some(Token::Variable(name)) => word.push(Expr::Variable(name)),
+            Some(Token::Glob(pattern)) => word.push(Expr::Glob(pattern)),
+            Some(Token::Tilde(name)) => word.push(Expr::Tilde(name)),
+            Some(Token::BraceOpen) => self.parse_brace(open, word)?,
+            Some(Token::CommandSubstitution(ast)) => word.push(Expr::CommandSubstitution(ast)),
+            Some(Token::LineSubstitution(ast)) => word.push(Expr::LineSubstitution(ast)),
+            Some(Token::Quoted(parts)) => word.push(Expr::Quoted(parts)),
+            _ => unreachable!(),
+        }
+        Ok(true)
+    }
+
+    /// Parse the alternatives in braces after the `{` at `open`. Braces with only one
+    /// alternative, like `{}`, are left as text, unless they hold a range like `{1..10}`.
+    fn parse_brace(&mut self, open: usize, word: &mut Vec<Expr>) -> ParseResult<()> {
+        let mut alternatives = vec![vec![]];
+
+        let close = loop {
+            match self.peek_token()? {
+                Some(&Token::Comma) => {
+                    self.next_token()?;
+                    alternatives.push(vec![]);
+                },
+                Some(&Token::BraceClose) => {
+                    let close = self.peeked.as_ref().unwrap().1.end;
+                    self.next_token()?;
+                    break close;
+                },
+                Some(_) => {
+                    if !self.parse_word_part(alternatives.last_mut().unwrap())? {
+                        return Err(self.unexpected_token("`,` or `}`"));
                     }
                 },
+                None => {
+                    let source = self.lexer.source;
+                    let span = open..open + 1;
+                    return Err(ParseError::new(source, ParseErrorKind::UnclosedDelimiter, span,
+                                               "a closing `}`"));
+                },
+            }
+        };
+
+        if alternatives.len() > 1 {
+            word.push(Expr::Brace(alternatives));
+            return Ok(());
+        }
 
-                Token::Text(text) => {
-                    current_arg.push(Expr::Text(text));
+        let alternative = alternatives.pop().unwrap();
+        if let [Expr::Text(ref text)] = alternative[..] {
+            match parse_range(text) {
+                Ok(Some(range)) => {
+                    let alternatives =
+                        range.into_iter().map(|value| vec![Expr::Text(value)]).collect();
+                    word.push(Expr::Brace(alternatives));
+                    return Ok(());
+                },
+                Ok(None) => {},
+                Err(RangeTooLong) => {
+                    let source = self.lexer.source;
+                    return Err(ParseError::new(source, ParseErrorKind::RangeTooLong, open..close,
+                                               "at most a million values"));
                 },
             }
         }
 
-        Ok(Ast::Call { command: command, args: args })
+        push_text(word, String::from("{"));
+        for expr in alternative {
+            match expr {
+                Expr::Text(text) => push_text(word, text),
+                expr => word.push(expr),
+            }
+        }
+        push_text(word, String::from("}"));
+        Ok(())
+    }
+}
+
+/// Add text to the end of a word, joining it to any text already there, so a word parses the
+/// same however its text was quoted.
+fn push_text(word: &mut Vec<Expr>, text: String) {
+    match word.last_mut() {
+        Some(Expr::Text(last)) => last.push_str(&text),
+        _ => word.push(Expr::Text(text)),
     }
 }
 
+/// The most values a range in braces can expand to.
+const MAX_RANGE_LEN: u128 = 1_000_000;
+
+/// A range in braces with more than `MAX_RANGE_LEN` values.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct RangeTooLong;
+
+/// Expand the inside of a range in braces, like `1..10`, `10..1`, `01..10` with its leading
+/// zeros, or `a..e`. Returns `None` if the text isn't a range.
+fn parse_range(text: &str) -> Result<Option<Vec<String>>, RangeTooLong> {
+    let (start, end) = match text.split_once("..") {
+        Some(split) => split,
+        None => return Ok(None),
+    };
+
+    if let (Ok(first), Ok(last)) = (start.parse::<i64>(), end.parse::<i64>()) {
+        if (i128::from(last) - i128::from(first)).unsigned_abs() >= MAX_RANGE_LEN {
+            return Err(RangeTooLong);
+        }
+        let padded = |s: &str| s.trim_start_matches('-').len() > 1 &&
+                               s.trim_start_matches('-').starts_with('0');
+        let width = if padded(start) || padded(end) { start.len().max(end.len()) } else { 0 };
+        let values: Vec<i64> = if first <= last {
+            (first..=last).collect()
+        } else {
+            (last..=first).rev().collect()
+        };
+        return Ok(Some(values.into_iter().map(|n| format!("{:01$}", n, width)).collect()));
+    }
+
+    let mut start_chars = start.chars();
+    let mut end_chars = end.chars();
+    match (start_chars.next(), start_chars.next(), end_chars.next(), end_chars.next()) {
+        (Some(first), None, Some(last), None) if first.is_ascii_alphabetic() &&
+                                                 last.is_ascii_alphabetic() => {
+            let values: Vec<char> = if first <= last {
+                (first..=last).collect()
+            } else {
+                (last..=first).rev().collect()
+            };
+            Ok(Some(values.into_iter().map(String::from).collect()))
+        },
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_range, Ast, Expr, Parser, RangeTooLong, RedirectTarget, Redirection};
+
+    fn parse(source: &str) -> Ast {
+        Parser::new(source).parse().unwrap()
+    }
+
+    fn text(text: &str) -> Vec<Expr> {
+        vec![Expr::Text(String::from(text))]
+    }
+
+    /// A call with plain text words and no redirections.
+    fn call(words: &[&str]) -> Ast {
+        Ast::Call {
+            command: text(words[0]),
+            args: words[1..].iter().map(|word| text(word)).collect(),
+            redirections: vec![],
+        }
+    }
+
+    fn range(text: &str) -> Vec<String> {
+        parse_range(text).unwrap().unwrap()
+    }
+
+    #[test]
+    fn numeric_ranges() {
+        assert_eq!(range("1..3"), ["1", "2", "3"]);
+        assert_eq!(range("-1..1"), ["-1", "0", "1"]);
+        assert_eq!(range("5..5"), ["5"]);
+    }
+
+    #[test]
+    fn reverse_ranges() {
+        assert_eq!(range("3..1"), ["3", "2", "1"]);
+        assert_eq!(range("c..a"), ["c", "b", "a"]);
+    }
+
+    #[test]
+    fn padded_ranges() {
+        assert_eq!(range("08..10"), ["08", "09", "10"]);
+        assert_eq!(range("1..010"), ["001", "002", "003", "004", "005", "006", "007", "008",
+                                     "009", "010"]);
+        assert_eq!(range("-01..1"), ["-01", "000", "001"]);
+    }
+
+    #[test]
+    fn letter_ranges() {
+        assert_eq!(range("a..e"), ["a", "b", "c", "d", "e"]);
+        assert_eq!(range("Y..b"), ["Y", "Z", "[", "\\", "]", "^", "_", "`", "a", "b"]);
+    }
+
+    #[test]
+    fn not_ranges() {
+        assert_eq!(parse_range("1.3"), Ok(None));
+        assert_eq!(parse_range("a..10"), Ok(None));
+        assert_eq!(parse_range("ab..c"), Ok(None));
+        assert_eq!(parse_range("1..2..3"), Ok(None));
+    }
+
+    #[test]
+    fn long_ranges_are_rejected() {
+        assert_eq!(parse_range("1..99999999999"), Err(RangeTooLong));
+        assert_eq!(parse_range("-9223372036854775808..9223372036854775807"), Err(RangeTooLong));
+        assert_eq!(range("1..1000000").len(), 1_000_000);
+        assert_eq!(parse_range("0..1000000"), Err(RangeTooLong));
+    }
+
+    #[test]
+    fn stray_keywords_are_shown_whole() {
+        let error = Parser::new("end").parse().unwrap_err();
+        assert_eq!(error.span, 0..3);
+        assert_eq!(error.to_string(), "unexpected `end`, expected a command");
+    }
+
+    #[test]
+    fn unclosed_brace_points_at_the_brace() {
+        let error = Parser::new("echo a{b").parse().unwrap_err();
+        assert_eq!(error.span, 6..7);
+        assert_eq!(error.to_string(), "unclosed `{`, expected a closing `}`");
+    }
+
+    #[test]
+    fn pipes() {
+        assert_eq!(parse("a | b x|c"),
+                   Ast::Pipeline(vec![call(&["a"]), call(&["b", "x"]), call(&["c"])]));
+    }
+
+    #[test]
+    fn redirections() {
+        let redirection = |fd, target| Redirection { fd, target };
+        assert_eq!(parse("cmd 2> err < in >> log > out 2>&1"), Ast::Call {
+            command: text("cmd"),
+            args: vec![],
+            redirections: vec![
+                redirection(2, RedirectTarget::Output(text("err"))),
+                redirection(0, RedirectTarget::Input(text("in"))),
+                redirection(1, RedirectTarget::Append(text("log"))),
+                redirection(1, RedirectTarget::Output(text("out"))),
+                redirection(2, RedirectTarget::Duplicate(1)),
+            ],
+        });
+
+        // A number is only a file descriptor if it's a whole word.
+        assert_eq!(parse("echo a2>b"), Ast::Call {
+            command: text("echo"),
+            args: vec![text("a2")],
+            redirections: vec![redirection(1, RedirectTarget::Output(text("b")))],
+        });
+    }
+
+    #[test]
+    fn sequences_and_conditionals() {
+        let and = |left, right| Ast::And(Box::new(left), Box::new(right));
+        let or = |left, right| Ast::Or(Box::new(left), Box::new(right));
+        assert_eq!(parse("a; b && c || d\ne"), Ast::Sequence(vec![
+            call(&["a"]),
+            or(and(call(&["b"]), call(&["c"])), call(&["d"])),
+            call(&["e"]),
+        ]));
+        assert_eq!(parse("a || b && c"), and(or(call(&["a"]), call(&["b"])), call(&["c"])));
+    }
+
+    #[test]
+    fn single_quotes() {
+        assert_eq!(parse("echo 'a  b' '' '$x \\'"), call(&["echo", "a  b", "", "$x \\"]));
+        assert_eq!(parse("echo 'it'\\''s' a'b'c"), call(&["echo", "it's", "abc"]));
+    }
+
+    #[test]
+    fn escapes_in_double_quotes() {
+        assert_eq!(parse("echo \"\\u{1F600}\\e[0m \\$x\""), call(&["echo", "\u{1F600}\x1b[0m $x"]));
+    }
+
+    #[test]
+    fn double_quote_interpolation() {
+        assert_eq!(parse("echo x\"a $y ${z}w $(p)\""), Ast::Call {
+            command: text("echo"),
+            args: vec![vec![
+                Expr::Text(String::from("x")),
+                Expr::Quoted(vec![
+                    Expr::Text(String::from("a ")),
+                    Expr::Variable(String::from("y")),
+                    Expr::Text(String::from(" ")),
+                    Expr::Variable(String::from("z")),
+                    Expr::Text(String::from("w ")),
+                    Expr::CommandSubstitution(Box::new(call(&["p"]))),
+                ]),
+            ]],
+            redirections: vec![],
+        });
+    }
+
+    #[test]
+    fn display_parses_back_to_the_same_ast() {
+        let sources = [
+            "a | b 2>&1 | c > out < in >> log",
+            "a; b && c || d; e &",
+            "echo 'a  b' '' \"it's\" \"\\e\\t\" \\| \\;",
+            "echo \"$x$y ${x}y $(a | b) \\$ \\\" \\\\\" $@(ls)",
+            "echo ~ ~user/x *.rs **/[ab]? {a,b{c,d}} {1..3} a{}",
+            "function f; if a; b; else if c; d; else; e; end; end",
+            "while a; for x in 1 $y; continue; end; end",
+        ];
+        for source in &sources {
+            let ast = parse(source);
+            assert_eq!(parse(&ast.to_string()), ast, "{} displayed as {}", source, ast);
+        }
+    }
+}
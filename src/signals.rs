@@ -0,0 +1,98 @@
+use libc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set when a child process changes state, so the job table only needs checking afterwards.
+static CHILD_CHANGED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sigchld(_signal: libc::c_int) {
+    CHILD_CHANGED.store(true, Ordering::SeqCst);
+}
+
+/// Whether a child has changed state since the last call.
+pub fn take_child_changed() -> bool {
+    CHILD_CHANGED.swap(false, Ordering::SeqCst)
+}
+
+/// Signals an interactive shell ignores, so that `Ctrl-C` and `Ctrl-\` only affect the command
+/// being run, and the shell isn't stopped when switching the terminal between jobs or when
+/// `Ctrl-Z` is pressed.
+const INTERACTIVE_SIGNALS: [libc::c_int; 5] = [
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGTSTP,
+    libc::SIGTTIN,
+    libc::SIGTTOU,
+];
+
+/// Whether `init` ignored the interactive signals, so children need the defaults restoring.
+static IGNORING: AtomicBool = AtomicBool::new(false);
+
+/// Install the shell's signal handlers. Interrupted system calls are restarted, so the rest of
+/// the shell doesn't have to deal with `EINTR` from them.
+pub fn init(interactive: bool) {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_sigchld as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGCHLD, &action, std::ptr::null_mut());
+
+        if interactive {
+            for &signal in &INTERACTIVE_SIGNALS {
+                libc::signal(signal, libc::SIG_IGN);
+            }
+            IGNORING.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Restore the default dispositions of the signals the shell ignores. Called in a child
+/// process between `fork` and `exec`, so it must only do async-signal-safe things. Signals
+/// ignored when a non-interactive shell was started stay ignored.
+pub fn reset_for_child() {
+    if !IGNORING.load(Ordering::SeqCst) {
+        return;
+    }
+    unsafe {
+        for &signal in &INTERACTIVE_SIGNALS {
+            libc::signal(signal, libc::SIG_DFL);
+        }
+    }
+}
+
+/// The conventional name of a signal, like `SIGSEGV`.
+pub fn name(signal: i32) -> String {
+    let name = match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGCHLD => "SIGCHLD",
+        libc::SIGCONT => "SIGCONT",
+        libc::SIGSTOP => "SIGSTOP",
+        libc::SIGTSTP => "SIGTSTP",
+        libc::SIGTTIN => "SIGTTIN",
+        libc::SIGTTOU => "SIGTTOU",
+        libc::SIGURG => "SIGURG",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        libc::SIGVTALRM => "SIGVTALRM",
+        libc::SIGPROF => "SIGPROF",
+        libc::SIGWINCH => "SIGWINCH",
+        libc::SIGIO => "SIGIO",
+        libc::SIGSYS => "SIGSYS",
+        _ => return format!("signal {}", signal),
+    };
+    String::from(name)
+}
@@ -0,0 +1,46 @@
+use libc;
+use std::env;
+use std::ffi::{CStr, CString};
+
+use dirs;
+use variables::Variables;
+
+/// The user's home directory, from `$HOME` or else the password database.
+pub fn home_dir(variables: &Variables) -> Option<String> {
+    match variables.get("HOME") {
+        Some(ref home) if !home.is_empty() => Some(home.join(" ")),
+        _ => env::home_dir().map(|home| home.to_string_lossy().into_owned()),
+    }
+}
+
+/// Another user's home directory, from the password database.
+fn user_home_dir(user: &str) -> Option<String> {
+    let user = CString::new(user).ok()?;
+    unsafe {
+        let passwd = libc::getpwnam(user.as_ptr());
+        if passwd.is_null() || (*passwd).pw_dir.is_null() {
+            return None;
+        }
+        Some(CStr::from_ptr((*passwd).pw_dir).to_string_lossy().into_owned())
+    }
+}
+
+/// Expand `~name`: `~` is the home directory, `~user` is that user's home directory, and `~+`
+/// and `~-` are the current and previous working directories.
+pub fn expand(name: &str, variables: &Variables) -> Result<String, String> {
+    match name {
+        "" => home_dir(variables).ok_or_else(|| String::from("couldn't find home dir")),
+        "+" => {
+            dirs::logical_cwd(variables)
+                .map(|dir| dir.to_string_lossy().into_owned())
+                .map_err(|e| format!("can't get the current directory: {}", e))
+        },
+        "-" => {
+            match variables.get("OLDPWD") {
+                Some(ref dir) if !dir.is_empty() => Ok(dir.join(" ")),
+                _ => Err(String::from("`~-` used but $OLDPWD isn't set")),
+            }
+        },
+        user => user_home_dir(user).ok_or_else(|| format!("no such user: {}", user)),
+    }
+}
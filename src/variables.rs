@@ -0,0 +1,147 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+
+/// A shell variable. Like in fish, every variable holds a list of values, so that `$files` can
+/// expand to several arguments without any word splitting.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Variable {
+    pub value: Vec<String>,
+    pub exported: bool,
+}
+
+/// The shell's variables, with lookups falling back to the environment the shell was started
+/// with.
+#[derive(Clone, Debug)]
+pub struct Variables {
+    /// The global scope followed by any nested local scopes, innermost last.
+    scopes: Vec<HashMap<String, Variable>>,
+
+    /// Environment variables which have been unset in the shell and must be removed from the
+    /// environment of child processes.
+    unset_env: HashSet<String>,
+}
+
+pub fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(is_name_char)
+}
+
+/// Variables which the shell sets itself and which can't be changed by builtins like `set`.
+pub fn is_read_only(name: &str) -> bool {
+    name == "status" || name == "pipestatus"
+}
+
+impl Variables {
+    pub fn new() -> Variables {
+        Variables {
+            scopes: vec![HashMap::new()],
+            unset_env: HashSet::new(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Vec<String>> {
+        if let Some(var) = self.lookup(name) {
+            return Some(var.value.clone());
+        }
+
+        if self.unset_env.contains(name) {
+            return None;
+        }
+
+        env::var(name).ok().map(|value| vec![value])
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Variable> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    fn lookup_mut(&mut self, name: &str) -> Option<&mut Variable> {
+        self.scopes.iter_mut().rev().find_map(|scope| scope.get_mut(name))
+    }
+
+    /// Assign to the innermost variable with this name, or create a global one. A variable
+    /// inherited from the environment stays exported.
+    pub fn set(&mut self, name: &str, value: Vec<String>) {
+        if let Some(var) = self.lookup_mut(name) {
+            var.value = value;
+            return;
+        }
+
+        let exported = !self.unset_env.contains(name) && env::var_os(name).is_some();
+        self.unset_env.remove(name);
+        self.scopes[0].insert(String::from(name), Variable { value, exported });
+    }
+
+    /// Create a variable in the innermost scope, shadowing any outer variable with this name.
+    pub fn set_local(&mut self, name: &str, value: Vec<String>) {
+        let scope = self.scopes.last_mut().unwrap();
+        scope.insert(String::from(name), Variable { value, exported: false });
+    }
+
+    /// Start a new innermost scope for local variables, like for a function call.
+    pub fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    /// Remove the innermost scope and its variables. The global scope is never removed.
+    pub fn pop_scope(&mut self) {
+        if self.scopes.len() > 1 {
+            self.scopes.pop();
+        }
+    }
+
+    /// Mark a variable as exported to child processes, creating it from its environment value
+    /// or as an empty global if it doesn't exist.
+    pub fn export(&mut self, name: &str) {
+        if self.lookup(name).is_none() {
+            let value = self.get(name).unwrap_or_default();
+            self.set(name, value);
+        }
+        self.lookup_mut(name).unwrap().exported = true;
+    }
+
+    /// Remove the innermost variable with this name. Returns false if there was no such
+    /// variable.
+    pub fn unset(&mut self, name: &str) -> bool {
+        for scope in self.scopes.iter_mut().rev() {
+            if scope.remove(name).is_some() {
+                return true;
+            }
+        }
+
+        if !self.unset_env.contains(name) && env::var_os(name).is_some() {
+            self.unset_env.insert(String::from(name));
+            return true;
+        }
+
+        false
+    }
+
+    /// All visible shell variables, sorted by name. Environment variables the shell hasn't
+    /// touched are not included.
+    pub fn all(&self) -> Vec<(&str, &Variable)> {
+        let mut names: Vec<&str> = self.scopes.iter()
+            .flat_map(|scope| scope.keys().map(|name| &name[..]))
+            .collect();
+        names.sort();
+        names.dedup();
+        names.into_iter().map(|name| (name, self.lookup(name).unwrap())).collect()
+    }
+
+    /// The exported variables to add to a child's environment, with list values joined by
+    /// spaces.
+    pub fn exported(&self) -> Vec<(&str, String)> {
+        self.all().into_iter()
+            .filter(|&(_, var)| var.exported)
+            .map(|(name, var)| (name, var.value.join(" ")))
+            .collect()
+    }
+
+    /// The environment variables to remove from a child's environment.
+    pub fn removed(&self) -> Vec<&str> {
+        self.unset_env.iter().map(|name| &name[..]).collect()
+    }
+}
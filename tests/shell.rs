@@ -0,0 +1,112 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+/// Run `shroom -c script` with `--norc`, returning its output.
+fn run(script: &str) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_shroom"))
+        .args(["--norc", "-c", script])
+        .output()
+        .unwrap()
+}
+
+/// Run shroom with `input` piped to its stdin as the script, returning its output.
+fn run_stdin(input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_shroom"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn exit_in_command_substitution_ends_only_the_substitution() {
+    let output = run("echo a$(echo b; exit 3; echo c)d; echo after");
+    assert_eq!(stdout(&output), "abd\nafter\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn exit_outside_command_substitution_ends_the_shell() {
+    let output = run("echo before; exit 4; echo after");
+    assert_eq!(stdout(&output), "before\n");
+    assert_eq!(output.status.code(), Some(4));
+}
+
+#[test]
+fn line_substitution_gives_a_value_per_line() {
+    let output = run("for line in $@(printf 'a b\\nc\\n') $@(true); echo \"<$line>\"; end");
+    assert_eq!(stdout(&output), "<a b>\n<c>\n");
+}
+
+#[test]
+fn status_starts_at_zero() {
+    let output = run("echo $status $pipestatus");
+    assert_eq!(stdout(&output), "0 0\n");
+}
+
+#[test]
+fn blocks_set_status_to_their_exit_code() {
+    let output = run("if false; echo x; end; echo $status; \
+                      for i in nomatch*.zz; end; echo $status");
+    assert_eq!(stdout(&output), "0\n1\n");
+}
+
+#[test]
+fn break_and_return_in_an_if_condition_skip_the_block() {
+    let output = run("for i in 1 2; if break; echo inside-if; end; end; \
+                      function f; if return 5; end; end; f; echo $status");
+    assert_eq!(stdout(&output), "5\n");
+}
+
+#[test]
+fn function_writing_more_than_a_pipe_holds_runs_alongside_the_pipeline() {
+    let output = run("function f; seq 100000; end; f | wc -l");
+    assert_eq!(stdout(&output).trim(), "100000");
+}
+
+#[test]
+fn piped_function_stops_when_the_next_command_stops_reading() {
+    let output = run("function f; yes; end; f | head -n1");
+    assert_eq!(stdout(&output), "y\n");
+}
+
+#[test]
+fn backgrounded_function_runs_alongside_the_rest_of_the_script() {
+    let output = run("function f; sleep 0.5; echo f; end; f &; echo main; wait");
+    assert_eq!(stdout(&output), "main\nf\n");
+}
+
+#[test]
+fn runaway_recursion_is_an_error() {
+    let output = run("function g; g; end; g; echo $status");
+    assert_eq!(stdout(&output), "1\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("functions called more than"));
+}
+
+#[test]
+fn commands_in_a_piped_script_can_read_the_lines_after_them() {
+    let output = run_stdin("head -n1\nhello\n");
+    assert_eq!(stdout(&output), "hello\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+}
+
+#[test]
+fn parse_errors_in_a_piped_script_go_to_stderr_with_their_line() {
+    let output = run_stdin("echo ok\necho )\n");
+    assert_eq!(stdout(&output), "ok\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--> <stdin>:2:6"));
+}
+
+#[test]
+fn background_jobs_are_listed_but_not_announced_outside_the_prompt() {
+    let output = run("sleep 0.2 &; jobs; wait");
+    assert_eq!(stdout(&output), "[1]+ Running    sleep 0.2 &\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+}